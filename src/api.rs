@@ -2,15 +2,24 @@
 use crate::Request;
 use crate::RequestMethod;
 use crate::Authentication;
+use crate::AuthMethod;
 use crate::CheckedParam;
 use crate::UnCheckedParam;
+use crate::CPRelation;
 use crate::S_EMPTY;
 use crate::ParamVal;
 use crate::ParameterType;
+use crate::ReturnPreference;
+use crate::FunctionResult;
 use crate::APIParam;
+use crate::api_file;
+use crate::lint;
 use crate::Schema;
+use crate::MuscleConfig;
+use crate::Response;
 
-use std::{fs::File, io::BufReader};
+use std::{fs::{self, File}, io::BufReader, path::PathBuf, sync::{Arc, Mutex}, time::SystemTime};
+use tokio_postgres::CancelToken;
 use log::{debug, error, info};
 
 //#[json]
@@ -28,20 +37,143 @@ pub struct API {
     token_name: String,
     pg_setvar_prefix: String,
     pub pg_set: String,
+    pub pg_set_keys: Vec<String>,   // settings that pg_set sets (SET LOCAL)
+    pub deviation_is_forbidden: bool,   // the deviation is a failed claim check (-> 403 rather than 400)
     request_set: bool,
     routing_json: Value,
     routing_file_path: String,
     routing_file_read: bool,
+    routing_files: Vec<PathBuf>,    // the file and the files it $refs, ...
+    routing_files_modified: Option<SystemTime>, // ... and when they last changed
     use_extended_url_relations: bool,
+    pg_role_allow: Vec<String>,     // roles a token may switch to through `pg_set_role`
+    pg_anon_role: String,           // role for routes without authentication, "none" for no role switch
+    api_key_header: String,         // x-auth-method api_key: where to find the key ...
+    api_key_query_param: String,
+    api_key_file: String,           // ... and how to check it: file with keys and claims ...
+    api_key_pg_function: String,    // ... or a postgres function (called in Response)
+    api_keys: Value,
+    auth_cookie_name: String,       // x-auth-method cookie_jwt: JWT comes in this cookie, ...
+    csrf_cookie_name: String,       // ... and POST, PATCH, DELETE need CSRF protection
+    csrf_header: String,
+    csrf_allowed_origins: Vec<String>,
+    scope_claim: String,            // claim with the scopes of OpenAPI security requirements
+    database_names: Vec<String>,    // configured databases, which x-database may name
+    statement_timeout: String,      // default for x-statement-timeout, or "none"
+    allow_unfiltered: bool,         // default for x-allow-unfiltered (DELETE, PATCH without where)
+    pub cancel_token: Arc<Mutex<Option<CancelToken>>>, // cancels this request's query (set in db)
     pub local_ip_address: String // corresponds to muscle.ini, no checks made. Needed for shutdown and reload requests
 }
 
+/// One entry of `x-claim-custom`, e.g.
+///
+/// ```
+/// "x-claim-custom": [
+///   {"name": "role", "checkval": ["sf_editor", "sf_admin"]},
+///   {"name": "roles", "contains": "sf_editor"},
+///   {"name": "level", "ge": 2},
+///   {"name": "dozent_id", "equals_param": "editor_id", "pg_set_as": "editor_id"}
+/// ],
+/// ```
+///
+/// All checks that are present must pass.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ClaimItem{
     name: String,
     value: Option<String>,
-    checkval: Option<String>,
-    pg_set_as: Option<String>
+    checkval: Option<Value>,        // claim equals this value, or one of the values in this array
+    contains: Option<Value>,        // claim is an array that contains this value
+    gt: Option<f64>,                // claim is a number (or numeric string) greater than ...
+    ge: Option<f64>,
+    lt: Option<f64>,
+    le: Option<f64>,
+    equals_param: Option<String>,   // claim equals the value of this query (or payload) parameter
+    pg_set_as: Option<String>,
+    pg_set_role: Option<bool>
+}
+
+impl ClaimItem{
+
+    /// Checks the claims of a token against this item.
+    ///
+    /// Err contains a description of the (first) failed check,
+    /// which includes a claim missing from the token.
+    fn check( &self, claims: &Value, request: &mut Request, b_use_extended_url: bool ) -> Result<(), String>{
+
+        // Nothing to check, e.g. item only for pg_set_as
+        if self.checkval.is_none() && self.contains.is_none() && self.equals_param.is_none() &&
+            self.gt.is_none() && self.ge.is_none() && self.lt.is_none() && self.le.is_none() { return Ok(()); }
+
+        let claim = match claims.get( &self.name ){
+            Some( c ) => c,
+            None => return Err( format!("claim `{}` is missing", self.name) )
+        };
+
+        if let Some( checkval ) = &self.checkval{
+            let b_ok = match checkval.as_array(){
+                Some( vals ) => vals.iter().any( |v| ClaimItem::is_same( claim, v ) ),
+                None => ClaimItem::is_same( claim, checkval )
+            };
+            if !b_ok { return Err( format!("claim `{}` does not match", self.name) ); }
+        }
+
+        if let Some( val ) = &self.contains{
+            let b_ok = match claim.as_array(){
+                Some( items ) => items.iter().any( |v| ClaimItem::is_same( v, val ) ),
+                None => false
+            };
+            if !b_ok { return Err( format!("claim `{}` does not contain {}", self.name, val) ); }
+        }
+
+        if self.gt.is_some() || self.ge.is_some() || self.lt.is_some() || self.le.is_some(){
+            let n = match ClaimItem::as_number( claim ){
+                Some( n ) => n,
+                None => return Err( format!("claim `{}` is not a number", self.name) )
+            };
+            if matches!( self.gt, Some( x ) if n <= x ) || matches!( self.ge, Some( x ) if n < x ) ||
+               matches!( self.lt, Some( x ) if n >= x ) || matches!( self.le, Some( x ) if n > x ){
+                return Err( format!("claim `{}` is out of range", self.name) );
+            }
+        }
+
+        if let Some( s_param ) = &self.equals_param{
+            let param_val = match request.get_query_parameter_value( s_param ){
+                Some( v ) if b_use_extended_url => {
+                    match UnCheckedParam::analyze_extended_val( v ){
+                        ( v, CPRelation::Equal ) => Some( v ),
+                        _ => None       // only =eq. identifies a value
+                    }
+                },
+                Some( v ) => Some( v.to_string() ),
+                None => request.get_payload_param( s_param ).map( ClaimItem::as_string )
+            };
+            match param_val{
+                Some( v ) if v == ClaimItem::as_string( claim ) => {},
+                _ => return Err( format!("claim `{}` does not match parameter `{}`", self.name, s_param) )
+            }
+        }
+        Ok(())
+    }
+
+    // Claims and configured values compare as strings, 
+    // so that 5 and "5" are the same.
+    fn is_same( a: &Value, b: &Value ) -> bool{
+        ClaimItem::as_string( a ) == ClaimItem::as_string( b )
+    }
+
+    fn as_string( v: &Value ) -> String{
+        match v.as_str(){
+            Some( s ) => s.to_string(),
+            None => v.to_string()
+        }
+    }
+
+    fn as_number( v: &Value ) -> Option<f64>{
+        match v.as_f64(){
+            Some( n ) => Some( n ),
+            None => v.as_str().and_then( |s| s.parse::<f64>().ok() )
+        }
+    }
 }
 
 impl API{
@@ -51,6 +183,8 @@ impl API{
 
     pub const SUPERFLUOUS_PARAMETER: &'static str = "superfluous_parm_not_present";
 
+    const ERR_UNFILTERED: &'static str = "Refusing to change all rows: this request needs a parameter to select rows (or the route x-allow-unfiltered)";
+
     const PARAM_TYPE_PAYLOAD:u8 = 0;
     const PARAM_TYPE_QUERY:u8 = 1;
 
@@ -60,7 +194,7 @@ impl API{
     ///
     /// The API struct remains initialized with the API. In order to check 
     /// a new request, call .set_request.
-    pub fn new( addr: &str, pg_token_name: &str, pg_setvar_prefix: &str, s_routing_file: &str, b_use_extended_url_relations: bool ) -> Self{
+    pub fn new( conf: &MuscleConfig ) -> Self{
 
         API{
             checked_query_parameters: vec![],
@@ -69,42 +203,126 @@ impl API{
             checked_post_parameters: vec![],
             problems_post_parameters: S_EMPTY,
             checked_post_params_read: false,
-            token_name: pg_token_name.to_string(),
-            pg_setvar_prefix: pg_setvar_prefix.to_string(),
+            token_name: conf.token_name.to_string(),
+            pg_setvar_prefix: conf.pg_setvar_prefix.to_string(),
             pg_set: "".to_string(),
-            routing_file_path: s_routing_file.to_string(),
+            pg_set_keys: vec![],
+            deviation_is_forbidden: false,
+            routing_file_path: conf.api_conf.to_string(),
             routing_file_read: false,
+            routing_files: vec![ PathBuf::from( &conf.api_conf ) ],
+            routing_files_modified: None,
             routing_json: serde_json::from_str("{}").unwrap(),
             request: Request::default(),
             request_set: false,
-            use_extended_url_relations: b_use_extended_url_relations,
-            local_ip_address: addr.to_string()
+            use_extended_url_relations: conf.use_eq_syntax_on_url_parameters,
+            pg_role_allow: conf.pg_role_allow.clone(),
+            pg_anon_role: conf.pg_anon_role.to_string(),
+            api_key_header: conf.api_key_header.to_string(),
+            api_key_query_param: conf.api_key_query_param.to_string(),
+            api_key_file: conf.api_key_file.to_string(),
+            api_key_pg_function: conf.api_key_pg_function.to_string(),
+            api_keys: Value::Null,
+            auth_cookie_name: conf.auth_cookie_name.to_string(),
+            csrf_cookie_name: conf.csrf_cookie_name.to_string(),
+            csrf_header: conf.csrf_header.to_string(),
+            csrf_allowed_origins: conf.csrf_allowed_origins.clone(),
+            scope_claim: conf.scope_claim.to_string(),
+            database_names: conf.get_database_names(),
+            statement_timeout: conf.statement_timeout.to_string(),
+            allow_unfiltered: conf.allow_unfiltered,
+            cancel_token: Arc::new( Mutex::new( None ) ),
+            local_ip_address: conf.addr.to_string()
         }
     }
 
     /// Read the OpenAPI file containing this server's endpoints
     /// (Set self.routing_file_read = false for a re-read)
-    fn read_api(&mut self){
+    pub fn read_api(&mut self){
 
         // only read it it is not already read
         if !self.routing_file_read {
+            info!("Reading routing table ...");
+            if let Err( e ) = self.load_api( false ){
+                error!("{}", e);
+            }
+            self.routing_file_read = true;
+        }
+    }
 
-            // Open the file in read-only mode 
-            let open_api_file = match File::open(&self.routing_file_path){
-                // @todo: consider process.exit(0)
-                Err( _e ) => panic!("Cannot find file with API configuration"),
-                Ok ( f ) => f
-            };
+    /// Re-reads the OpenAPI file (after a change, SIGHUP, or 
+    /// a reload request). The new API replaces the one in use only if 
+    /// it has no errors (see `lint::check_api`); otherwise the API in
+    /// use is kept and Err lists the problems.
+    pub fn reload_api(&mut self) -> Result<(), String>{
+        info!("Reloading routing table ...");
+        let res = self.load_api( true );
+        match &res{
+            Ok( _ ) => info!("Routing table reloaded from `{}`", self.routing_file_path),
+            Err( e ) => error!("Reload refused, keeping the API in use: {}", e)
+        }
+        res
+    }
 
-            info!("Reading routing table (again?) ...");
-            self.routing_json = match serde_json::from_reader( BufReader::new( open_api_file )){
-                // @todo: consider process.exit(0)
-                Err( _ ) => panic!("Cannot parse file with API configuration"),
-                Ok( api ) => api
-            };
+    /// Has the OpenAPI file (or a file it refers to) changed since 
+    /// it was last read?
+    pub fn is_api_file_changed(&self) -> bool{
+        self.routing_file_read && API::get_files_modified( &self.routing_files ) != self.routing_files_modified
+    }
 
-            self.routing_file_read = true;
+    fn get_files_modified( files: &[PathBuf] ) -> Option<SystemTime>{
+        files.iter().map( |f| fs::metadata( f ).and_then( |m| m.modified() ).ok() )
+            .collect::<Option<Vec<SystemTime>>>()?
+            .into_iter().max()
+    }
+
+    /// Reads the API into a temporary and swaps it in. With
+    /// `b_require_valid`, an API with errors is refused.
+    fn load_api(&mut self, b_require_valid: bool) -> Result<(), String>{
+        // Remember the time even if reading fails, so that a broken 
+        // file is not re-read until it changes again
+        self.routing_files_modified = API::get_files_modified( &self.routing_files );
+
+        let ( api, files ) = api_file::read_api_files( &self.routing_file_path )?;
+        let mut problems = lint::check_api( &api );
+        problems.append( &mut lint::check_databases( &api, &self.database_names ) );
+        let problems: Vec<String> = problems.iter()
+            .filter( |p| p.is_error ).map( |p| p.to_string() ).collect();
+        if b_require_valid && !problems.is_empty() {
+            return Err( format!("{} error(s) in `{}`: {}", problems.len(), self.routing_file_path, problems.join( "; " )) );
         }
+
+        self.routing_files_modified = API::get_files_modified( &files );
+        self.routing_files = files;
+        self.routing_json = api;
+        self.routing_file_read = true;
+        self.read_api_keys();
+        Ok(())
+    }
+
+    /// Reads and parses the OpenAPI file (see `api_file`); Err 
+    /// describes what went wrong (incl. line and column of a syntax error).
+    pub fn read_api_file( s_path: &str ) -> Result<Value, String>{
+        api_file::read_api_file( s_path )
+    }
+
+    /// Read the file with API keys (if configured), which maps
+    /// each key to the claims it is granted, e.g.
+    ///
+    /// ```
+    /// { "a7c3...": {"name": "nightly_import", "role": "sf_importer"} }
+    /// ```
+    ///
+    /// The keys are re-read together with the OpenAPI file.
+    fn read_api_keys( &mut self ){
+        if self.api_key_file == "none" { return; }
+        self.api_keys = match File::open( &self.api_key_file ){
+            Err( e ) => { error!("Cannot open file with API keys `{}`: {}", self.api_key_file, e); Value::Null },
+            Ok( f ) => match serde_json::from_reader( BufReader::new( f ) ){
+                Ok( keys ) => keys,
+                Err( e ) => { error!("Cannot parse file with API keys `{}`: {}", self.api_key_file, e); Value::Null }
+            }
+        };
     }
 
     /// If POST or PATCH is used but SELECT syntax needed for db query
@@ -142,7 +360,7 @@ impl API{
         self.request = request.clone();
         if request.is_reload_config{
             info!("Request to reload the openAPI endpoint configuration.");
-            self.routing_file_read = false;
+            let _ = self.reload_api();
         }
         self.read_api(); // usually does nothing
         self.check_rerouting( );
@@ -222,45 +440,76 @@ impl API{
             if !self.request.has_valid_auth() {return 
                 String::from("API requires valid authentication for this request, but none was found");}
 
+            // Browsers send cookies with any request, also with those
+            // that other sites trigger: changes need CSRF protection
+            if self.request.auth_method == AuthMethod::COOKIE && self.request.method != RequestMethod::GET {
+                if let Err( e ) = API::check_csrf( &self.request, &self.csrf_cookie_name, &self.csrf_header, &self.csrf_allowed_origins ){
+                    info!("CSRF check failed: {}", e);
+                    self.deviation_is_forbidden = true;
+                    return e;
+                }
+            }
+
             let auth_claim_items = self.get_auth_claim_items_from_api( );
             let mut pg_set = "".to_string();
+            let mut pg_set_keys = vec![];
             for i in auth_claim_items{
                 info!("Items: {:?}", i);
-                match i.checkval{
-                    Some (val) => {
-                        info!("...need to check if >{}< is >{}<", i.name, val);
-                        let b_ok = match &self.request.auth_claim{
-                            Some (e) => e.get( &i.name ).expect("").as_str().expect("") == val,
-                            None => false
-                        };
-                        info!(" ... result: {}", b_ok);
-                        if !b_ok {return String::from("Invalid authentication, check token or API");}
-                    }
-                    None => {}
-                };
-                match i.pg_set_as{
+                let claims = self.request.auth_claim.clone().unwrap_or( Value::Null );
+                if let Err( e ) = i.check( &claims, &mut self.request, self.use_extended_url_relations ){
+                    info!(" ... claim check failed: {}", e);
+                    self.deviation_is_forbidden = true;
+                    return format!("Invalid authentication, check token or API ({})", e);
+                }
+                match &i.pg_set_as{
                     Some( val ) => {
-                        let pg_val_to_set = match &self.request.auth_claim{
-                            Some (e) => {
-                                info!("WWWA? {:?} <- {}", e, &i.name);
-                                // Problem: https://docs.serde.rs/serde_json/value/enum.Value.html
-                                // is_number tut nicht mit as_str
-                                //let x: String = e.get( &i.name ).expect("").into();
-                                let x: String = e.get( &i.name ).expect("").to_string();
-                                x
-                            },
-                            None => "".to_string()
+                        // Problem: https://docs.serde.rs/serde_json/value/enum.Value.html
+                        // is_number tut nicht mit as_str
+                        let pg_val_to_set = match claims.get( &i.name ){
+                            Some( x ) => x.to_string(),
+                            None => {
+                                self.deviation_is_forbidden = true;
+                                return format!("Invalid authentication, check token or API (claim `{}` is missing)", i.name);
+                            }
                         };
                         if pg_val_to_set != ""{
                             pg_set = format!("{}; SET LOCAL {}.{}='{}';", pg_set, self.pg_setvar_prefix, val, pg_val_to_set);
+                            pg_set_keys.push( format!("{}.{}", self.pg_setvar_prefix, val) );
                             info!("pushing: SET {}.{}'='{}';", self.pg_setvar_prefix, val, pg_val_to_set);
                         }
                     }
                     None => {}
                 };
+                if i.pg_set_role == Some( true ){
+                    let s_role = match &self.request.auth_claim{
+                        Some (e) => e.get( &i.name ).and_then( |r| r.as_str() ).unwrap_or( "" ).to_string(),
+                        None => "".to_string()
+                    };
+                    match API::get_set_role_sql( &s_role, &self.pg_role_allow ){
+                        Some( sql ) => {
+                            info!("pushing: {}", sql);
+                            pg_set = format!("{}; {}", pg_set, sql);
+                        },
+                        None => {
+                            info!("Role >{}< from claim `{}` is not in pg_role_allow", s_role, i.name);
+                            self.deviation_is_forbidden = true;
+                            return String::from("Invalid authentication, check token or API");
+                        }
+                    }
+                }
             };
 
             self.pg_set = pg_set;
+            self.pg_set_keys = pg_set_keys;
+
+            // OpenAPI `security`: scopes required for this route
+            let claims = self.request.auth_claim.clone().unwrap_or( Value::Null );
+            if let Err( e ) = API::check_scopes( &self.get_security_requirements(), 
+                &self.routing_json[ "components" ][ "securitySchemes" ], &claims, &self.scope_claim ){
+                info!("Scope check failed: {}", e);
+                self.deviation_is_forbidden = true;
+                return e;
+            }
 
             // (1) if there are Checkvals, check them and throw Exceptions on violation
             // (2) the pg_set_as need to go to db in order to set variables on the client.
@@ -285,6 +534,9 @@ impl API{
             },
             RequestMethod::DELETE => {
                 self.get_checked_query_params();
+                if self.problems_query_parameters.is_empty() && self.is_unfiltered_refused() {
+                    return API::ERR_UNFILTERED.to_string();
+                }
                 self.problems_query_parameters.to_owned()
             },
             RequestMethod::PATCH => {
@@ -295,6 +547,9 @@ impl API{
                 }else{
                     let mut tmp = self.problems_query_parameters.to_owned();
                     tmp.push_str( &self.problems_post_parameters.to_owned());    // error msges
+                    if tmp.is_empty() && self.is_unfiltered_refused() {
+                        return API::ERR_UNFILTERED.to_string();
+                    }
                     tmp
                 }
             },
@@ -332,6 +587,36 @@ impl API{
         checked_post_values
     }
 
+    /// Postgres function that maps an API key to its claims
+    /// (`api_key_pg_function` in .ini), or None.
+    pub fn get_api_key_pg_function( &self ) -> Option<&str>{
+        match &self.api_key_pg_function[..]{
+            "none" | "" => None,
+            f => Some( f )
+        }
+    }
+
+    /// Role to switch to for requests that do not need
+    /// authentication (`pg_anon_role` in .ini), or None
+    /// if the configured value is "none" (or missing).
+    pub fn get_pg_anon_role( &self ) -> Option<&str>{
+        match &self.pg_anon_role[..]{
+            "none" | "" => None,
+            role => Some( role )
+        }
+    }
+
+    /// `SET LOCAL ROLE "role";` if `s_role` is in the allow-list, 
+    /// None otherwise (an empty allow-list permits no role at all).
+    ///
+    /// The allow-list comes from the .ini (`pg_role_allow`), so the
+    /// quoted role name is no user input that reaches the database
+    /// unchecked.
+    fn get_set_role_sql( s_role: &str, allow: &[String] ) -> Option<String>{
+        if s_role.is_empty() || !allow.iter().any( |r| r == s_role ) { return None; }
+        Some( format!("SET LOCAL ROLE \"{}\";", s_role) )
+    }
+
     /// Name of token to set in database
     /// The name is configured in .env, e.g. token_name=pg_request_token.
     /// This leads to a SET pg_request_token = <Request-TOKEN>
@@ -395,6 +680,14 @@ impl API{
 
             // separate problematic from conforming parameters
             self.split_problems_query_parms( &tmp );
+
+            // function arguments are named, not compared (x-function)
+            if self.is_function_call() {
+                for par in tmp.iter().filter( |p| p.relation != CPRelation::Equal && p.relation != CPRelation::Unknown ){
+                    self.problems_query_parameters.push_str( &format!("parameter \"{}\" is a function argument, \
+                        which takes no other relation than eq", par.name) );
+                }
+            }
             self.checked_query_params_read = true;
         }
     }
@@ -430,6 +723,354 @@ impl API{
         }
     }
 
+    // ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+    // Serve the API configuration itself (.ini: api_doc_path)
+
+    /// The OpenAPI document as loaded, for clients to see 
+    /// which routes and parameters there are:
+    ///
+    /// (1) `servers` is set to this service's address (or to 
+    ///     api_doc_server_url if so configured),
+    ///
+    /// (2) if api_doc_filter_by_token is true, only operations 
+    ///     that the caller's token (bearer, cookie or API key) 
+    ///     grants access to are listed (parameter dependent 
+    ///     claim checks, equals_param, are not evaluated here),
+    ///
+    /// (3) if api_doc_strip_extensions is true, all `x-` 
+    ///     extensions are removed.
+    pub fn get_api_doc( &mut self, conf: &MuscleConfig ) -> Value{
+        self.read_api();
+        let mut doc = self.routing_json.clone();
+
+        let s_server = match &conf.api_doc_server_url[..]{
+            "none" | "" => format!("{}://{}:{}{}", if conf.server_use_https {"https"} else {"http"}, conf.addr, conf.port,
+                if conf.api_prefix.is_empty() { "".to_string() } else { format!("/{}", conf.api_prefix) }),
+            url => url.to_string()
+        };
+        doc[ "servers" ] = serde_json::json!([ {"url": s_server} ]);
+
+        if conf.api_doc_filter_by_token {
+            let claims = self.get_caller_claims();
+            if let Some( paths ) = doc[ API::API_PATHS ].as_object_mut(){
+                for ( _, path ) in paths.iter_mut(){
+                    if let Some( ops ) = path.as_object_mut(){
+                        ops.retain( |_, op| self.is_accessible( op, &claims ) );
+                    }
+                }
+                paths.retain( |_, path| path.as_object().is_none_or( |ops| !ops.is_empty() ) );
+            }
+        }
+
+        if conf.api_doc_strip_extensions { API::strip_extensions( &mut doc ); }
+        doc
+    }
+
+    /// Claims of the caller for the API documentation, taken from
+    /// the bearer token, the auth cookie or the API key (in this order).
+    fn get_caller_claims( &mut self ) -> Option<Value>{
+        if self.request.has_valid_auth() { return self.request.auth_claim.clone(); }
+
+        let mut request = self.request.clone();
+        if let Some( s_token ) = request.get_cookie( &self.auth_cookie_name ).map( |t| t.to_string() ){
+            request.set_cookie_auth( &s_token );
+            if request.has_valid_auth() { return request.auth_claim; }
+        }
+
+        let s_key = self.get_api_key_from_request();
+        match s_key.is_empty(){
+            true => None,
+            false => self.api_keys.get( &s_key ).cloned()
+        }
+    }
+
+    /// Could a caller with these claims use operation `op`?
+    /// (Claim checks that depend on request parameters are skipped)
+    fn is_accessible( &self, op: &Value, claims: &Option<Value> ) -> bool{
+        let s_auth_method = match op[ "x-auth-method" ].as_str(){
+            Some( m ) => m.to_string(),
+            None => self.get_auth_methods_from_requirements( &self.get_security_requirements_of( op ) )
+                .into_iter().next().unwrap_or( S_EMPTY )
+        };
+        if !matches!( &s_auth_method[..], "forward_jwt_bearer" | "api_key" | "cookie_jwt" ) { return true; }
+
+        let claims = match claims{
+            Some( c ) => c,
+            None => return false
+        };
+
+        let items: Vec<ClaimItem> = serde_json::from_value( op[ "x-claim-custom" ].clone() ).unwrap_or_default();
+        let mut request = Request::default();
+        for mut i in items{
+            i.equals_param = None;
+            if i.check( claims, &mut request, false ).is_err() { return false; }
+            if i.pg_set_role == Some( true ) {
+                let s_role = claims[ &i.name ].as_str().unwrap_or( "" );
+                if API::get_set_role_sql( s_role, &self.pg_role_allow ).is_none() { return false; }
+            }
+        }
+
+        API::check_scopes( &self.get_security_requirements_of( op ), 
+            &self.routing_json[ "components" ][ "securitySchemes" ], claims, &self.scope_claim ).is_ok()
+    }
+
+    /// Remove all `x-` extensions (recursively)
+    fn strip_extensions( doc: &mut Value ){
+        match doc{
+            Value::Object( o ) => {
+                o.retain( |key, _| !key.starts_with( "x-" ) );
+                o.values_mut().for_each( API::strip_extensions );
+            },
+            Value::Array( a ) => a.iter_mut().for_each( API::strip_extensions ),
+            _ => {}
+        }
+    }
+
+    /// Property of this request's route and method in the 
+    /// API, e.g. `get_route_property( "x-set-auth-cookie" )`;
+    /// Value::Null if it is not configured.
+    pub fn get_route_property( &self, s_name: &str ) -> &Value{
+        &self.routing_json[ API::API_PATHS ]
+            [ &self.request.url ]
+            [ Request::get_method_as_str(self.request.method) ]
+            [ s_name ]
+    }
+
+    /// Database of this request's route, set through `x-database` 
+    /// on the operation or at the top of the OpenAPI file (None: the 
+    /// database configured for this API).
+    pub fn get_database( &self ) -> Option<&str>{
+        self.get_route_property( "x-database" ).as_str()
+            .or_else( || self.routing_json[ "x-database" ].as_str() )
+    }
+
+    /// Statement timeout of this request's route (`x-statement-timeout`, 
+    /// e.g. "30s" or 500 (ms)), or the configured default; None if
+    /// there is none. Values that postgres would not take are logged
+    /// and ignored.
+    pub fn get_statement_timeout( &self ) -> Option<String>{
+        let s_timeout = match self.get_route_property( "x-statement-timeout" ){
+            Value::String( s ) => s.to_string(),
+            Value::Number( n ) => n.to_string(),
+            _ => self.statement_timeout.to_string()
+        };
+        if s_timeout == "none" { return None; }
+
+        // digits, optionally followed by a unit
+        let s_unit = s_timeout.trim_start_matches( |c: char| c.is_ascii_digit() );
+        match s_unit.len() < s_timeout.len() && ["", "ms", "s", "min", "h", "d"].contains( &s_unit ){
+            true => Some( s_timeout ),
+            false => { error!("Invalid statement timeout `{}` (e.g. 500, 500ms, 30s, 2min) is ignored", s_timeout); None }
+        }
+    }
+
+    /// Would this DELETE or PATCH change all rows (no query parameter,
+    /// hence no `where`), although neither `x-allow-unfiltered` on the
+    /// route nor `allow_unfiltered` in the .ini permits that?
+    /// (PATCHes calling a function are no updates.)
+    fn is_unfiltered_refused( &self ) -> bool{
+        let b_allowed = self.get_route_property( "x-allow-unfiltered" ).as_bool().unwrap_or( self.allow_unfiltered );
+        !b_allowed && self.checked_query_parameters.is_empty() 
+            && self.request.method_reroute != RequestMethod::POSTorPATCHasGET
+    }
+
+    /// Most rows a DELETE or PATCH may change (`x-max-affected-rows`); 
+    /// if there are more, the change is rolled back.
+    pub fn get_max_affected_rows( &self ) -> Option<u64>{
+        match self.request.method{
+            RequestMethod::DELETE | RequestMethod::PATCH => self.get_route_property( "x-max-affected-rows" ).as_u64(),
+            _ => None
+        }
+    }
+
+    /// `return=...` of this request's header `Prefer` (None if there
+    /// is no such preference, or none that is supported)
+    pub fn get_prefer_return( &self ) -> Option<ReturnPreference>{
+        self.request.get_header( "Prefer" )?.split( [',', ';'] )
+            .filter_map( |p| p.trim().strip_prefix( "return=" ) )
+            .find_map( ReturnPreference::from )
+    }
+
+    /// Column holding the version of a row (`x-version-column`): the
+    /// ETag of a single row, which PATCH and DELETE check for If-Match
+    pub fn get_version_column( &self ) -> Option<&str>{
+        self.get_route_property( "x-version-column" ).as_str()
+    }
+
+    /// `If-Match` of a PATCH or DELETE: change the rows only if they
+    /// are still as the client has seen them (ETag, otherwise 412)
+    pub fn get_if_match( &self ) -> Option<&str>{
+        match self.request.method{
+            RequestMethod::PATCH if self.request.method_reroute == RequestMethod::POSTorPATCHasGET => None,
+            RequestMethod::PATCH | RequestMethod::DELETE => self.request.get_header( "If-Match" ),
+            _ => None
+        }
+    }
+
+    /// The version from `If-Match` (its first ETag, without quotes), if
+    /// the route has an `x-version-column` to compare it with. None for
+    /// `*` and hash ETags, which are compared with the current rows
+    pub fn get_if_match_version( &self ) -> Option<String>{
+        self.get_version_column()?;
+        let s_etag = self.get_if_match()?.split( ',' ).next()?.trim();
+        match s_etag == "*" || Response::is_hash_etag( s_etag ){
+            true => None,
+            false => Some( s_etag.trim_start_matches( "W/" ).trim_matches( '"' ).to_string() )
+        }
+    }
+
+    /// Does this request call a function: a GET with its query parameters
+    /// as arguments (`x-function: true`), rather than select from a view
+    /// or table with them as criteria, or a POST or PATCH with its body
+    /// (`x-query-syntax-of-method: GET`)?
+    pub fn is_function_call( &self ) -> bool{
+        match self.request.method{
+            RequestMethod::GET => self.get_route_property( "x-function" ) == &Value::Bool( true ),
+            _ => self.request.method_reroute == RequestMethod::POSTorPATCHasGET
+        }
+    }
+
+    /// What this function call responds with (`x-returns`): all rows 
+    /// as JSON array unless the route says otherwise
+    pub fn get_function_result( &self ) -> FunctionResult{
+        match self.is_function_call(){
+            true => self.get_route_property( "x-returns" ).as_str().and_then( FunctionResult::from ).unwrap_or( FunctionResult::ARRAY ),
+            false => FunctionResult::ARRAY
+        }
+    }
+
+    /// Content-Type of what this function call returns (`x-content-type`,
+    /// e.g. text/html with `x-returns: raw`); None: JSON
+    pub fn get_content_type( &self ) -> Option<&str>{
+        match self.is_function_call(){
+            true => self.get_route_property( "x-content-type" ).as_str(),
+            false => None
+        }
+    }
+
+    /// Argument of the function that this POST or PATCH calls 
+    /// (`x-query-syntax-of-method: GET`) which takes the whole request
+    /// body as jsonb (`x-body-as-single-argument`), rather than one
+    /// argument per property.
+    pub fn get_body_argument( &self ) -> Option<&str>{
+        match self.request.method_reroute{
+            RequestMethod::POSTorPATCHasGET => self.get_route_property( "x-body-as-single-argument" ).as_str(),
+            _ => None
+        }
+    }
+
+    /// The body to pass as x-body-as-single-argument: the properties of
+    /// the request body that the API declares (see get_checked_post_params), 
+    /// with their values as sent. Others are left out.
+    pub fn get_body_argument_value( &mut self ) -> String{
+        let names: Vec<String> = self.get_checked_post_params().iter().map( |p| p.name.to_string() ).collect();
+        let body: serde_json::Map<String, Value> = names.into_iter()
+            .filter_map( |s_name| self.request.get_payload_param( &s_name ).cloned().map( |v| ( s_name, v ) ) )
+            .collect();
+        Value::Object( body ).to_string()
+    }
+
+    /// Is this request an insert (a POST that calls no function)?
+    pub fn is_insert( &self ) -> bool{
+        self.request.method == RequestMethod::POST && self.request.method_reroute != RequestMethod::POSTorPATCHasGET
+    }
+
+    /// What this POST, PATCH or DELETE is to respond with: the 
+    /// preference of the request, or else of the route (x-prefer-return).
+    /// Only a POST can be answered headers-only; for PATCH and DELETE
+    /// that is minimal. Calls of functions (x-query-syntax-of-method)
+    /// have no preference.
+    pub fn get_return_preference( &self ) -> ReturnPreference{
+        let b_changes_rows = match self.request.method{
+            RequestMethod::POST => self.is_insert(),
+            RequestMethod::PATCH => self.request.method_reroute != RequestMethod::POSTorPATCHasGET,
+            RequestMethod::DELETE => true,
+            _ => false
+        };
+        if !b_changes_rows { return ReturnPreference::DEFAULT; }
+
+        let preference = self.get_prefer_return()
+            .or_else( || self.get_route_property( "x-prefer-return" ).as_str().and_then( ReturnPreference::from ) )
+            .unwrap_or( ReturnPreference::DEFAULT );
+        match preference{
+            ReturnPreference::HEADERSONLY if self.request.method != RequestMethod::POST => ReturnPreference::MINIMAL,
+            p => p
+        }
+    }
+
+    /// The preference of the request's `Prefer` header, if it is the 
+    /// one applied (see get_return_preference): for `Preference-Applied`
+    pub fn get_applied_preference( &self ) -> Option<ReturnPreference>{
+        let preference = self.get_return_preference();
+        match preference != ReturnPreference::DEFAULT && self.get_prefer_return() == Some( preference ){
+            true => Some( preference ),
+            false => None
+        }
+    }
+
+    /// Where to GET a row that this request created: the GET route
+    /// of the same table or view (operationId; this route's path if it
+    /// has one), with the row's primary key (`x-primary-key`, e.g.
+    /// `["kapitel_id"]`) as query parameters -- `/kapitel?kapitel_id=eq.4`.
+    /// None if the route has no primary key, or there is no GET route.
+    pub fn get_location( &self, row: &Value, s_prefix: &str ) -> Option<String>{
+        let primary_key = API::get_key_columns( self.get_route_property( "x-primary-key" ) );
+        if primary_key.is_empty() { return None; }
+        let row = match row.as_array(){
+            Some( rows ) => rows.first()?,
+            None => row
+        };
+
+        let operation_id = self.get_route_property( API::API_QUERY );
+        let paths = self.routing_json[ API::API_PATHS ].as_object()?;
+        let s_path = match &paths.get( &self.request.url )?[ "get" ][ API::API_QUERY ] == operation_id{
+            true => &self.request.url,
+            false => paths.iter().find( |( _, item )| &item[ "get" ][ API::API_QUERY ] == operation_id )?.0
+        };
+
+        let mut query = vec![];
+        for s_key in primary_key{
+            let s_val = match &row[ s_key ]{
+                Value::String( s ) => s.to_string(),
+                Value::Null => return None,
+                v => v.to_string()
+            };
+            query.push( format!("{}={}{}", s_key, if self.use_extended_url_relations {"eq."} else {""}, urlencoding::encode( &s_val )) );
+        }
+        Some( format!("{}/{}?{}", if s_prefix.is_empty() { "".to_string() } else { format!("/{}", s_prefix) }, s_path, query.join( "&" )) )
+    }
+
+    /// Columns that order the rows of this request's path, so that
+    /// GET and the If-Match check (see get_db_locking_get_sql) hash 
+    /// them alike: the `x-primary-key` of the route, or else of another
+    /// operation on the path. Empty if there is none.
+    pub fn get_order_key( &self ) -> Vec<&str>{
+        let key = match self.get_route_property( "x-primary-key" ){
+            Value::Null => self.routing_json[ API::API_PATHS ][ &self.request.url ].as_object()
+                .and_then( |ops| ops.values().map( |op| &op[ "x-primary-key" ] ).find( |k| !k.is_null() ) ),
+            key => Some( key )
+        };
+        key.map( API::get_key_columns ).unwrap_or_default()
+    }
+
+    /// Column names of an `x-primary-key`: a name or an array of names
+    fn get_key_columns( key: &Value ) -> Vec<&str>{
+        match key{
+            Value::String( s ) => vec![ &s[..] ],
+            Value::Array( a ) => a.iter().filter_map( |k| k.as_str() ).collect(),
+            _ => vec![]
+        }
+    }
+
+    /// May this request run on a replica of the database? GET
+    /// requests and routes with `x-read-only: true` may, unless 
+    /// the request has the header `s_primary_header` (e.g. to read 
+    /// what it has just written).
+    pub fn may_use_replica( &self, s_primary_header: &str ) -> bool{
+        ( self.request.method == RequestMethod::GET || self.get_route_property( "x-read-only" ) == &Value::Bool( true ) )
+            && self.request.get_header( s_primary_header ).is_none()
+    }
+
     // ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
     // split problems from CheckedParams
 
@@ -519,25 +1160,31 @@ impl API{
 
         if param_type == API::PARAM_TYPE_PAYLOAD {
 
-            // get parameter definition from $ref in openAPI schema
-            let s_pointer = match self.routing_json[ API::API_PATHS ]
+            // get parameter definition from the schema, given inline
+            // or as $ref to components (external refs are inlined on reading)
+            let schema = &self.routing_json[ API::API_PATHS ]
                 [ s_path ]
                 [ s_method ]
                 [ "requestBody" ]
                 [ "content" ]
                 [ "application/json" ]
-                [ "schema" ]
-                [ "$ref" ].as_str(){
-                    Some (a) => &a[1..],
-                    _ => ""
-                };
-            
-            if s_pointer == "" { return None };
+                [ "schema" ];
+
+            let s_pointer = match schema[ "$ref" ].as_str(){
+                Some (a) => &a[1..],
+                _ => ""
+            };
+
+            let schema = match s_pointer{
+                "" => schema,
+                _ => self.routing_json.pointer( s_pointer ).unwrap_or( &Value::Null )
+            };
+
+            if schema == &Value::Null { return None };
 
             // The properties of this object. I guess because 
             // of the iteration below, they must be flat.
-            let s_props = self.routing_json.pointer( &(s_pointer.to_owned() + 
-                    &"/properties".to_owned()) ).unwrap_or(&Value::Null);
+            let s_props = &schema[ "properties" ];
 
             // Wenn der Pointer keine Ergebnisse liefert, gilt die API als nicht fertig
             // konfiguriert. Es wird "No such route" an den Server geliefert und der Fehler
@@ -549,8 +1196,7 @@ impl API{
             
             // The properties that are *required* are listed in an extra 
             // array (openAPI spec https://swagger.io/docs/specification/describing-request-body/)
-            let s_required_sub: &Value = self.routing_json.pointer( &(s_pointer.to_owned() + 
-                    &"/required".to_owned()) ).unwrap_or( &Value::Null );
+            let s_required_sub: &Value = &schema[ "required" ];
 
             // If no parameters of the object are marked as required,
             // there's nothing to check
@@ -667,23 +1313,575 @@ impl API{
         self.checked_post_parameters = vec![];
         self.problems_post_parameters = S_EMPTY;
         self.checked_post_params_read = false;
+        self.deviation_is_forbidden = false;
         self.request_set = false;
+        *self.cancel_token.lock().unwrap() = None;
     }
 
     /// Sets the "api_needs_auth" flag
     /// in the request, if the api for
     /// this request contains 
     /// "x-auth-method":"forward_jwt_bearer",
+    /// or "x-auth-method":"api_key".
+    ///
+    /// For API keys, the key replaces the bearer token, 
+    /// and the claims are looked up in the keys file. (If the
+    /// key is checked by a postgres function instead, the claims
+    /// stay empty here and are set in Response.)
+    ///
+    /// Routes without "x-auth-method" take the method from 
+    /// their OpenAPI `security` requirement (see
+    /// get_auth_method_from_security).
     fn check_auth_need( &mut self ){
-        if self.routing_json[ API::API_PATHS ]
-            [ &self.request.url ]
-            [ Request::get_method_as_str(self.request.method) ]
-            [ "x-auth-method" ] == "forward_jwt_bearer" {
+        let s_auth_method = match self.get_route_property( "x-auth-method" ).as_str(){
+            Some( m ) => m.to_string(),
+            None => self.get_auth_method_from_security()
+        };
+
+        match &s_auth_method[..]{
+            "forward_jwt_bearer" => {
                 info!("...needs JWT authentication");
                 self.request.api_needs_auth = Authentication::NEEDED;
-        }else{
+                self.request.auth_method = AuthMethod::JWTBEARER;
+            },
+            "cookie_jwt" => {
+                info!("...needs JWT authentication (cookie)");
+                self.request.api_needs_auth = Authentication::NEEDED;
+                let s_token = self.request.get_cookie( &self.auth_cookie_name ).unwrap_or( "" ).to_string();
+                self.request.set_cookie_auth( &s_token );
+            },
+            "api_key" => {
+                info!("...needs API key");
+                self.request.api_needs_auth = Authentication::NEEDED;
+                let s_key = self.get_api_key_from_request();
+                let claims = match s_key.is_empty(){
+                    true => None,
+                    false => self.api_keys.get( &s_key ).cloned()
+                };
+                self.request.set_api_key_auth( &s_key, claims );
+            },
+            _ => {
                 self.request.api_needs_auth = Authentication::NOTNEEDED;
+            }
+        }
+    }
+
+    /// The OpenAPI security requirements for this request: the 
+    /// operation's `security` or, if there is none, the document's.
+    fn get_security_requirements( &self ) -> Vec<Value>{
+        self.get_security_requirements_of( &self.routing_json[ API::API_PATHS ]
+            [ &self.request.url ]
+            [ Request::get_method_as_str(self.request.method) ] )
+    }
 
+    /// Security requirements of operation `op` (see get_security_requirements)
+    fn get_security_requirements_of( &self, op: &Value ) -> Vec<Value>{
+        let security = if op[ "security" ].is_null() { &self.routing_json[ "security" ] } else { &op[ "security" ] };
+        security.as_array().cloned().unwrap_or_default()
+    }
+
+    /// x-auth-method corresponding to the security requirements: they 
+    /// are alternatives, so it is the method of the first scheme for 
+    /// which the request brings credentials (a bearer token, the auth
+    /// cookie or an API key) -- or of the first scheme, if it brings none.
+    /// The method of a scheme is
+    ///
+    /// * http (bearer), oauth2, openIdConnect -> forward_jwt_bearer,
+    /// * apiKey in a cookie -> cookie_jwt,
+    /// * apiKey in header or query -> api_key,
+    ///
+    /// unless the scheme itself has an `x-auth-method`. Empty 
+    /// if there are no requirements or if one of them is empty
+    /// (which, in OpenAPI, makes authentication optional).
+    fn get_auth_method_from_security( &mut self ) -> String{
+        let methods = self.get_auth_methods_from_requirements( &self.get_security_requirements() );
+        for s_method in &methods{
+            if self.has_credentials_for( s_method ) { return s_method.to_string(); }
+        }
+        methods.into_iter().next().unwrap_or( S_EMPTY )
+    }
+
+    /// x-auth-methods of the schemes of all requirements, in order
+    /// (see get_auth_method_from_security)
+    fn get_auth_methods_from_requirements( &self, requirements: &[Value] ) -> Vec<String>{
+        if requirements.iter().any( |r| r.as_object().is_none_or( |o| o.is_empty() ) ) { return vec![]; }
+
+        requirements.iter()
+            .filter_map( |r| r.as_object() )
+            .flat_map( |o| o.keys() )
+            .map( |s_scheme| self.get_auth_method_of_scheme( s_scheme ) )
+            .filter( |s_method| !s_method.is_empty() )
+            .collect()
+    }
+
+    fn get_auth_method_of_scheme( &self, s_scheme: &str ) -> String{
+        let scheme = &self.routing_json[ "components" ][ "securitySchemes" ][ s_scheme ];
+        if let Some( m ) = scheme[ "x-auth-method" ].as_str() { return m.to_string(); }
+        match ( scheme[ "type" ].as_str().unwrap_or(""), scheme[ "in" ].as_str().unwrap_or("") ){
+            ( "http", _ ) | ( "oauth2", _ ) | ( "openIdConnect", _ ) => "forward_jwt_bearer".to_string(),
+            ( "apiKey", "cookie" ) => "cookie_jwt".to_string(),
+            ( "apiKey", _ ) => "api_key".to_string(),
+            ( t, _ ) => { error!("Security scheme `{}` has unsupported type `{}`", s_scheme, t); S_EMPTY }
+        }
+    }
+
+    /// Does the request bring credentials for x-auth-method `s_method`?
+    fn has_credentials_for( &mut self, s_method: &str ) -> bool{
+        match s_method{
+            "forward_jwt_bearer" => !self.request.get_auth().is_empty(),
+            "cookie_jwt" => self.request.get_cookie( &self.auth_cookie_name ).is_some(),
+            "api_key" => !self.get_api_key_from_request().is_empty(),
+            _ => false
+        }
+    }
+
+    /// Checks the scopes of the OpenAPI security requirements
+    /// against the claims: the request is allowed if *one* of the 
+    /// requirements is met, and a requirement is met if the claims 
+    /// contain *all* scopes that it lists for its schemes.
+    ///
+    /// Scopes are read from the claim that the security scheme names
+    /// as `x-scope-claim`, or else from `s_default_claim` (.ini: scope_claim).
+    /// The claim can be a space separated string (as in OAuth2's "scope")
+    /// or an array (as in "roles").
+    fn check_scopes( requirements: &[Value], schemes: &Value, claims: &Value, s_default_claim: &str ) -> Result<(), String>{
+        if requirements.is_empty() { return Ok(()); }
+
+        let b_ok = requirements.iter().any( |requirement| {
+            match requirement.as_object(){
+                Some( req ) => req.iter().all( |(s_scheme, scopes)| {
+                    let s_claim = schemes[ s_scheme ][ "x-scope-claim" ].as_str().unwrap_or( s_default_claim );
+                    let granted = API::get_claim_scopes( claims, s_claim );
+                    scopes.as_array().is_none_or( |needed| needed.iter()
+                        .all( |scope| granted.iter().any( |g| Some( &g[..] ) == scope.as_str() ) ) )
+                }),
+                None => false
+            }
+        });
+
+        match b_ok{
+            true => Ok(()),
+            false => Err( "Token lacks the scopes that the API requires for this request".to_string() )
+        }
+    }
+
+    fn get_claim_scopes( claims: &Value, s_claim: &str ) -> Vec<String>{
+        match &claims[ s_claim ]{
+            Value::String( s ) => s.split_whitespace().map( |x| x.to_string() ).collect(),
+            Value::Array( a ) => a.iter().filter_map( |x| x.as_str() ).map( |x| x.to_string() ).collect(),
+            _ => vec![]
         }
     }
+
+    /// CSRF protection for requests authenticated by cookie:
+    ///
+    /// (1) double submit: the value of the CSRF cookie (which scripts
+    ///     of other sites cannot read) must be repeated in the CSRF header,
+    ///
+    /// (2) the Origin (or else the Referer) must be one of the allowed origins.
+    ///
+    /// Each check applies if it is configured (csrf_cookie_name not "none",
+    /// csrf_allowed_origins not empty); if neither is, the request is rejected.
+    fn check_csrf( request: &Request, s_cookie: &str, s_header: &str, allowed_origins: &[String] ) -> Result<(), String>{
+        let b_double_submit = s_cookie != "none";
+        if !b_double_submit && allowed_origins.is_empty() {
+            error!("Cookie authentication for changes needs csrf_cookie_name or csrf_allowed_origins in the .ini file");
+            return Err( "CSRF protection is not configured".to_string() );
+        }
+
+        if b_double_submit {
+            match ( request.get_cookie( s_cookie ), request.get_header( s_header ) ){
+                ( Some( c ), Some( h ) ) if !c.is_empty() && c == h => {},
+                _ => return Err( "CSRF token missing or invalid".to_string() )
+            }
+        }
+
+        if !allowed_origins.is_empty() {
+            let s_origin = match request.get_header( "origin" ){
+                Some( o ) => o.to_string(),
+                // Referer is a full url: scheme://host[:port]/path...
+                None => request.get_header( "referer" ).unwrap_or( "" )
+                    .splitn( 4, '/' ).take( 3 ).collect::<Vec<&str>>().join( "/" )
+            };
+            if !allowed_origins.iter().any( |o| o.trim_end_matches('/') == s_origin ) {
+                return Err( format!("Origin `{}` is not allowed", s_origin) );
+            }
+        }
+        Ok(())
+    }
+
+    /// The API key from the configured header or, if 
+    /// there is none, from the configured query parameter.
+    fn get_api_key_from_request( &mut self ) -> String{
+        if self.api_key_header != "none" {
+            if let Some( key ) = self.request.get_header( &self.api_key_header ){ return key.to_string(); }
+        }
+        if self.api_key_query_param != "none" {
+            if let Some( key ) = self.request.get_query_parameter_value( &self.api_key_query_param ){ return key.to_string(); }
+        }
+        S_EMPTY
+    }
+}
+
+#[cfg(test)]
+impl API{
+    /// An API for tests with the routes in `s_routing_json`; neither
+    /// sf_muscle.ini nor an API file are read (also not by set_request)
+    pub fn from_routing_json( s_routing_json: &str ) -> Self{
+        let mut api = API::new( &crate::get_test_conf() );
+        api.routing_json = serde_json::from_str( s_routing_json ).unwrap();
+        api.routing_file_read = true;
+        api
+    }
+
+    /// Sets a request given as HTTP text (see set_request), e.g.
+    /// `api.set_test_request( "GET /kapitel HTTP/1.1\r\n\r\n" ).get_database()`
+    pub fn set_test_request( &mut self, s_request: &str ) -> &mut Self{
+        self.set_request( &Request::new( s_request, "::1", "127.0.0.1", "", "static" ) );
+        self
+    }
+}
+
+#[cfg(test)]
+mod test_set_role{
+    use super::*;
+
+    #[test]
+    fn allowed() {
+        let allow = vec!["sf_editor".to_string(), "sf_reader".to_string()];
+        assert_eq!( API::get_set_role_sql( "sf_editor", &allow ), Some( "SET LOCAL ROLE \"sf_editor\";".to_string() ));
+    }
+
+    #[test]
+    fn not_allowed() {
+        let allow = vec!["sf_editor".to_string()];
+        assert_eq!( API::get_set_role_sql( "postgres", &allow ), None );
+        assert_eq!( API::get_set_role_sql( "", &allow ), None );
+        assert_eq!( API::get_set_role_sql( "sf_editor\"; drop table x; --", &allow ), None );
+    }
+
+    #[test]
+    fn empty_allow_list() {
+        assert_eq!( API::get_set_role_sql( "sf_editor", &[] ), None );
+    }
+}
+
+#[cfg(test)]
+mod test_claim_items{
+    use super::*;
+
+    fn item( s_json: &str ) -> ClaimItem{
+        serde_json::from_str( s_json ).unwrap()
+    }
+
+    fn claims() -> Value{
+        serde_json::from_str( r#"{"role": "sf_editor", "roles": ["sf_reader", "sf_editor"], "level": 3, "dozent_id": 17}"# ).unwrap()
+    }
+
+    #[test]
+    fn checkval() {
+        let mut r = Request::new( "GET /kapitel HTTP/1.1", "::1", "127.0.0.1", "", "static" );
+        assert_eq!( item( r#"{"name": "role", "checkval": "sf_editor"}"# ).check( &claims(), &mut r, false ), Ok(()) );
+        assert_eq!( item( r#"{"name": "role", "checkval": ["sf_admin", "sf_editor"]}"# ).check( &claims(), &mut r, false ), Ok(()) );
+        assert!( item( r#"{"name": "role", "checkval": ["sf_admin"]}"# ).check( &claims(), &mut r, false ).is_err() );
+        assert!( item( r#"{"name": "missing", "checkval": "x"}"# ).check( &claims(), &mut r, false ).is_err() );
+        assert_eq!( item( r#"{"name": "missing", "pg_set_as": "x"}"# ).check( &claims(), &mut r, false ), Ok(()) );
+    }
+
+    #[test]
+    fn contains() {
+        let mut r = Request::new( "GET /kapitel HTTP/1.1", "::1", "127.0.0.1", "", "static" );
+        assert_eq!( item( r#"{"name": "roles", "contains": "sf_editor"}"# ).check( &claims(), &mut r, false ), Ok(()) );
+        assert!( item( r#"{"name": "roles", "contains": "sf_admin"}"# ).check( &claims(), &mut r, false ).is_err() );
+        assert!( item( r#"{"name": "role", "contains": "sf_editor"}"# ).check( &claims(), &mut r, false ).is_err() );
+    }
+
+    #[test]
+    fn numbers() {
+        let mut r = Request::new( "GET /kapitel HTTP/1.1", "::1", "127.0.0.1", "", "static" );
+        assert_eq!( item( r#"{"name": "level", "ge": 3, "lt": 4}"# ).check( &claims(), &mut r, false ), Ok(()) );
+        assert!( item( r#"{"name": "level", "gt": 3}"# ).check( &claims(), &mut r, false ).is_err() );
+        assert!( item( r#"{"name": "role", "gt": 3}"# ).check( &claims(), &mut r, false ).is_err() );
+    }
+
+    #[test]
+    fn equals_param() {
+        let i = item( r#"{"name": "dozent_id", "equals_param": "editor_id"}"# );
+        let mut r = Request::new( "GET /kapitel?editor_id=17 HTTP/1.1", "::1", "127.0.0.1", "", "static" );
+        assert_eq!( i.check( &claims(), &mut r, false ), Ok(()) );
+        let mut r = Request::new( "GET /kapitel?editor_id=eq.17 HTTP/1.1", "::1", "127.0.0.1", "", "static" );
+        assert_eq!( i.check( &claims(), &mut r, true ), Ok(()) );
+        let mut r = Request::new( "GET /kapitel?editor_id=gt.1 HTTP/1.1", "::1", "127.0.0.1", "", "static" );
+        assert!( i.check( &claims(), &mut r, true ).is_err() );
+        let mut r = Request::new( "GET /kapitel?editor_id=18 HTTP/1.1", "::1", "127.0.0.1", "", "static" );
+        assert!( i.check( &claims(), &mut r, false ).is_err() );
+        let mut r = Request::new( "PATCH /kapitel HTTP/1.1\n\n{\"editor_id\": 17}", "::1", "127.0.0.1", "", "static" );
+        assert_eq!( i.check( &claims(), &mut r, false ), Ok(()) );
+    }
+}
+
+#[cfg(test)]
+mod test_csrf{
+    use super::*;
+
+    fn request( s_headers: &str ) -> Request{
+        Request::new( &format!("PATCH /kapitel HTTP/1.1\r\n{}\r\n\r\n{{}}", s_headers), "::1", "127.0.0.1", "", "static" )
+    }
+
+    #[test]
+    fn double_submit() {
+        let r = request( "Cookie: t=ey; csrf=abc\r\nX-CSRF-Token: abc" );
+        assert_eq!( API::check_csrf( &r, "csrf", "X-CSRF-Token", &[] ), Ok(()) );
+        let r = request( "Cookie: t=ey; csrf=abc\r\nX-CSRF-Token: abd" );
+        assert!( API::check_csrf( &r, "csrf", "X-CSRF-Token", &[] ).is_err() );
+        let r = request( "Cookie: t=ey; csrf=abc" );
+        assert!( API::check_csrf( &r, "csrf", "X-CSRF-Token", &[] ).is_err() );
+        let r = request( "Cookie: t=ey; csrf=\r\nX-CSRF-Token: " );
+        assert!( API::check_csrf( &r, "csrf", "X-CSRF-Token", &[] ).is_err() );
+    }
+
+    #[test]
+    fn origin() {
+        let allowed = vec!["https://sf.uni-heidelberg.de".to_string()];
+        let r = request( "Origin: https://sf.uni-heidelberg.de" );
+        assert_eq!( API::check_csrf( &r, "none", "X-CSRF-Token", &allowed ), Ok(()) );
+        let r = request( "Referer: https://sf.uni-heidelberg.de/kapitel/4" );
+        assert_eq!( API::check_csrf( &r, "none", "X-CSRF-Token", &allowed ), Ok(()) );
+        let r = request( "Origin: https://evil.example.org" );
+        assert!( API::check_csrf( &r, "none", "X-CSRF-Token", &allowed ).is_err() );
+        let r = request( "Host: sf.uni-heidelberg.de" );
+        assert!( API::check_csrf( &r, "none", "X-CSRF-Token", &allowed ).is_err() );
+    }
+
+    #[test]
+    fn not_configured() {
+        let r = request( "Origin: https://sf.uni-heidelberg.de" );
+        assert!( API::check_csrf( &r, "none", "X-CSRF-Token", &[] ).is_err() );
+    }
+}
+
+#[cfg(test)]
+mod test_scopes{
+    use super::*;
+
+    fn json( s: &str ) -> Value{
+        serde_json::from_str( s ).unwrap()
+    }
+
+    #[test]
+    fn scope_string() {
+        let schemes = json( r#"{"bearer": {"type": "http", "scheme": "bearer"}}"# );
+        let claims = json( r#"{"scope": "kapitel:read kapitel:write"}"# );
+        assert_eq!( API::check_scopes( &[json( r#"{"bearer": ["kapitel:write"]}"# )], &schemes, &claims, "scope" ), Ok(()) );
+        assert_eq!( API::check_scopes( &[json( r#"{"bearer": []}"# )], &schemes, &claims, "scope" ), Ok(()) );
+        assert!( API::check_scopes( &[json( r#"{"bearer": ["buch:write"]}"# )], &schemes, &claims, "scope" ).is_err() );
+        assert!( API::check_scopes( &[json( r#"{"bearer": ["kapitel:write", "buch:write"]}"# )], &schemes, &claims, "scope" ).is_err() );
+    }
+
+    #[test]
+    fn one_of_requirements() {
+        let schemes = json( r#"{"bearer": {"type": "http", "x-scope-claim": "roles"}}"# );
+        let claims = json( r#"{"roles": ["sf_editor"]}"# );
+        let requirements = [json( r#"{"bearer": ["sf_admin"]}"# ), json( r#"{"bearer": ["sf_editor"]}"# )];
+        assert_eq!( API::check_scopes( &requirements, &schemes, &claims, "scope" ), Ok(()) );
+        assert!( API::check_scopes( &requirements[..1], &schemes, &claims, "scope" ).is_err() );
+    }
+
+    #[test]
+    fn no_requirements() {
+        assert_eq!( API::check_scopes( &[], &Value::Null, &Value::Null, "scope" ), Ok(()) );
+    }
+
+    #[test]
+    fn auth_method_of_requirements() {
+        let mut api = API::from_routing_json( r#"{"components": {"securitySchemes": {"cookieAuth": {"type": "apiKey", "in": "cookie", 
+            "name": "pg_api_muscle_token"}, "bearerAuth": {"type": "http", "scheme": "bearer"}}},
+            "security": [{"cookieAuth": []}, {"bearerAuth": []}], "paths": {"kapitel": {"get": {}}}}"# );
+        assert_eq!( api.set_test_request( "GET /kapitel HTTP/1.1\r\nAuthorization: Bearer abc\r\n\r\n" ).request.auth_method, AuthMethod::JWTBEARER );
+        assert_eq!( api.set_test_request( "GET /kapitel HTTP/1.1\r\nCookie: pg_api_muscle_token=abc\r\n\r\n" ).request.auth_method, AuthMethod::COOKIE );
+        assert_eq!( api.set_test_request( "GET /kapitel HTTP/1.1\r\n\r\n" ).request.auth_method, AuthMethod::COOKIE );
+        assert_eq!( api.request.api_needs_auth, Authentication::NEEDED );
+    }
+}
+
+#[cfg(test)]
+mod test_api_doc{
+    use super::*;
+
+    #[test]
+    fn strip_extensions() {
+        let mut doc: Value = serde_json::from_str( r#"{"paths": {"kapitel": {"get": {"operationId": "web_api.kapitel", 
+            "x-auth-method": "forward_jwt_bearer", "parameters": [{"name": "a", "x-b": 1}]}}}, "x-top": true}"# ).unwrap();
+        API::strip_extensions( &mut doc );
+        assert_eq!( doc.to_string(), r#"{"paths":{"kapitel":{"get":{"operationId":"web_api.kapitel","parameters":[{"name":"a"}]}}}}"# );
+    }
+}
+
+#[cfg(test)]
+mod test_statement_timeout{
+    use super::*;
+
+    #[test]
+    fn statement_timeout() {
+        let mut api = API::from_routing_json( r#"{"paths": {"suche": {"get": {"x-statement-timeout": "30s"}}, 
+            "zahl": {"get": {"x-statement-timeout": 500}}, "falsch": {"get": {"x-statement-timeout": "1s; drop table x"}},
+            "kapitel": {"get": {}}}}"# );
+        api.statement_timeout = "none".to_string();
+        assert_eq!( api.set_test_request( "GET /suche HTTP/1.1\r\n\r\n" ).get_statement_timeout(), Some( "30s".to_string() ) );
+        assert_eq!( api.set_test_request( "GET /zahl HTTP/1.1\r\n\r\n" ).get_statement_timeout(), Some( "500".to_string() ) );
+        assert_eq!( api.set_test_request( "GET /falsch HTTP/1.1\r\n\r\n" ).get_statement_timeout(), None );
+        assert_eq!( api.set_test_request( "GET /kapitel HTTP/1.1\r\n\r\n" ).get_statement_timeout(), None );
+        api.statement_timeout = "2min".to_string();
+        assert_eq!( api.get_statement_timeout(), Some( "2min".to_string() ) );
+        api.statement_timeout = "min".to_string();
+        assert_eq!( api.get_statement_timeout(), None );
+    }
+}
+
+#[cfg(test)]
+mod test_unfiltered{
+    use super::*;
+
+    #[test]
+    fn unfiltered() {
+        let mut api = API::from_routing_json( r#"{"paths": {"alle": {"delete": {"x-allow-unfiltered": true, "x-max-affected-rows": 10}}, 
+            "kapitel": {"delete": {}, "patch": {}}, "suche": {"patch": {"x-query-syntax-of-method": "GET"}}}}"# );
+        api.allow_unfiltered = true;
+        assert!( !api.set_test_request( "DELETE /kapitel HTTP/1.1\r\n\r\n" ).is_unfiltered_refused() );
+        api.allow_unfiltered = false;
+        assert!( api.is_unfiltered_refused() );
+        assert_eq!( api.get_max_affected_rows(), None );
+        assert!( api.set_test_request( "PATCH /kapitel HTTP/1.1\r\n\r\n{}" ).is_unfiltered_refused() );
+        assert!( !api.set_test_request( "DELETE /alle HTTP/1.1\r\n\r\n" ).is_unfiltered_refused() );
+        assert_eq!( api.get_max_affected_rows(), Some( 10 ) );
+        assert!( !api.set_test_request( "PATCH /suche HTTP/1.1\r\n\r\n{}" ).is_unfiltered_refused() );
+    }
+}
+
+#[cfg(test)]
+mod test_function_call{
+    use super::*;
+
+    #[test]
+    fn function_call() {
+        let s_param = r#"[{"name": "buch_id", "description": "integer", "in": "query", "required": true, "schema": {"type": "integer", "format": "int32"}}]"#;
+        let mut api = API::from_routing_json( &format!( r#"{{"paths": {{"toc": {{"get": {{"operationId": "web_api.toc", "x-function": true, "parameters": {}}}}}, 
+            "kapitel": {{"get": {{"operationId": "web_api.kapitel", "parameters": {}}}}}}}}}"#, s_param, s_param ) );
+        assert!( api.set_test_request( "GET /toc?buch_id=eq.1 HTTP/1.1\r\n\r\n" ).is_function_call() );
+        assert_eq!( api.get_request_deviation(), S_EMPTY );
+        assert_eq!( api.get_checked_query_param_vals().len(), 1 );
+        assert!( !api.set_test_request( "GET /kapitel?buch_id=eq.1 HTTP/1.1\r\n\r\n" ).is_function_call() );
+        assert_eq!( api.get_request_deviation(), S_EMPTY );
+        assert!( !api.set_test_request( "GET /toc HTTP/1.1\r\n\r\n" ).get_request_deviation().is_empty() );
+        assert!( !api.set_test_request( "GET /toc?buch_id=lt.1 HTTP/1.1\r\n\r\n" ).get_request_deviation().is_empty() );
+        assert_eq!( api.set_test_request( "GET /kapitel?buch_id=lt.1 HTTP/1.1\r\n\r\n" ).get_request_deviation(), S_EMPTY );
+    }
+
+    #[test]
+    fn body_argument() {
+        let mut api = API::from_routing_json( r#"{"paths": {"toc": {"post": {"operationId": "web_api.buch_put_toc", 
+            "x-query-syntax-of-method": "GET", "x-body-as-single-argument": "toc"}, "patch": {"x-body-as-single-argument": "toc"}}}}"# );
+        assert_eq!( api.set_test_request( "POST /toc HTTP/1.1\r\n\r\n{}" ).get_body_argument(), Some( "toc" ) );
+        assert_eq!( api.set_test_request( "PATCH /toc HTTP/1.1\r\n\r\n{}" ).get_body_argument(), None );
+    }
+
+    #[test]
+    fn body_argument_value() {
+        let mut api = API::from_routing_json( r#"{"paths": {"toc": {"post": {"operationId": "web_api.buch_put_toc", 
+            "x-query-syntax-of-method": "GET", "x-body-as-single-argument": "toc", "requestBody": {"content": {"application/json": 
+            {"schema": {"type": "object", "properties": {"buch_id": {"type": "integer"}, "kapitel": {"type": "array"}}, 
+            "required": ["buch_id"]}}}}}}}}"# );
+        api.set_test_request( "POST /toc HTTP/1.1\r\n\r\n{\"buch_id\": 1, \"kapitel\": [{\"titel\": \"Eins\"}], \"editor_id\": 17}" );
+        assert_eq!( api.get_request_deviation(), S_EMPTY );
+        assert_eq!( api.get_body_argument_value(), r#"{"buch_id":1,"kapitel":[{"titel":"Eins"}]}"# );
+    }
+
+    #[test]
+    fn function_result() {
+        let mut api = API::from_routing_json( r#"{"paths": {"seite": {"get": {"operationId": "web_api.seite", "x-function": true,
+            "x-returns": "raw", "x-content-type": "text/html"}, "post": {"x-query-syntax-of-method": "GET", "x-returns": "scalar"}},
+            "kapitel": {"get": {"x-returns": "raw", "x-content-type": "text/html"}}}}"# );
+        assert_eq!( api.set_test_request( "GET /seite HTTP/1.1\r\n\r\n" ).get_function_result(), FunctionResult::RAW );
+        assert_eq!( api.get_content_type(), Some( "text/html" ) );
+        assert_eq!( api.set_test_request( "POST /seite HTTP/1.1\r\n\r\n{}" ).get_function_result(), FunctionResult::SCALAR );
+        assert_eq!( api.get_content_type(), None );
+        assert_eq!( api.set_test_request( "GET /kapitel HTTP/1.1\r\n\r\n" ).get_function_result(), FunctionResult::ARRAY );
+        assert_eq!( api.get_content_type(), None );
+    }
+}
+
+#[cfg(test)]
+mod test_return_preference{
+    use super::*;
+
+    fn get_api() -> API {
+        API::from_routing_json( r#"{"paths": {
+            "kapitel": {"get": {"operationId": "web_api.kapitel"}, "delete": {"operationId": "web_api.kapitel"},
+                "post": {"operationId": "web_api.kapitel", "x-primary-key": ["kapitel_id", "titel"]}},
+            "neu": {"post": {"operationId": "web_api.kapitel", "x-primary-key": "kapitel_id", "x-prefer-return": "headers-only"}},
+            "suche": {"post": {"operationId": "web_api.suche", "x-query-syntax-of-method": "GET"}}}}"# )
+    }
+
+    #[test]
+    fn preference() {
+        let mut api = get_api();
+        assert_eq!( api.set_test_request( "POST /kapitel HTTP/1.1\r\n\r\n{}" ).get_return_preference(), ReturnPreference::DEFAULT );
+        assert_eq!( api.set_test_request( "POST /kapitel HTTP/1.1\r\nPrefer: count=exact, return=minimal\r\n\r\n{}" ).get_return_preference(), ReturnPreference::MINIMAL );
+        assert_eq!( api.set_test_request( "DELETE /kapitel HTTP/1.1\r\nPrefer: return=representation\r\n\r\n" ).get_return_preference(), ReturnPreference::REPRESENTATION );
+        assert_eq!( api.set_test_request( "DELETE /kapitel HTTP/1.1\r\nPrefer: return=headers-only\r\n\r\n" ).get_return_preference(), ReturnPreference::MINIMAL );
+        assert_eq!( api.set_test_request( "POST /neu HTTP/1.1\r\n\r\n{}" ).get_return_preference(), ReturnPreference::HEADERSONLY );
+        assert_eq!( api.set_test_request( "POST /neu HTTP/1.1\r\nPrefer: return=representation\r\n\r\n{}" ).get_return_preference(), ReturnPreference::REPRESENTATION );
+        assert_eq!( api.set_test_request( "POST /suche HTTP/1.1\r\nPrefer: return=minimal\r\n\r\n{}" ).get_return_preference(), ReturnPreference::DEFAULT );
+        assert_eq!( api.set_test_request( "GET /kapitel HTTP/1.1\r\nPrefer: return=minimal\r\n\r\n" ).get_return_preference(), ReturnPreference::DEFAULT );
+    }
+
+    #[test]
+    fn applied_preference() {
+        let mut api = get_api();
+        assert_eq!( api.set_test_request( "POST /kapitel HTTP/1.1\r\nPrefer: return=minimal\r\n\r\n{}" ).get_applied_preference(), Some( ReturnPreference::MINIMAL ) );
+        assert_eq!( api.set_test_request( "DELETE /kapitel HTTP/1.1\r\nPrefer: return=headers-only\r\n\r\n" ).get_applied_preference(), None );
+        assert_eq!( api.set_test_request( "POST /neu HTTP/1.1\r\n\r\n{}" ).get_applied_preference(), None );
+        assert_eq!( api.set_test_request( "POST /suche HTTP/1.1\r\nPrefer: return=minimal\r\n\r\n{}" ).get_applied_preference(), None );
+    }
+
+    #[test]
+    fn location() {
+        let mut api = get_api();
+        api.use_extended_url_relations = true;
+        api.set_test_request( "POST /neu HTTP/1.1\r\n\r\n{}" );
+        assert_eq!( api.get_location( &serde_json::json!({"kapitel_id": 4, "titel": "Vier"}), "" ), Some( "/kapitel?kapitel_id=eq.4".to_string() ) );
+        api.set_test_request( "POST /kapitel HTTP/1.1\r\n\r\n{}" );
+        assert_eq!( api.get_location( &serde_json::json!([{"kapitel_id": 4, "titel": "Vier & fünf"}]), "v2" ), 
+            Some( "/v2/kapitel?kapitel_id=eq.4&titel=eq.Vier%20%26%20f%C3%BCnf".to_string() ) );
+        assert_eq!( api.get_location( &serde_json::json!({"kapitel_id": 4}), "" ), None );
+        api.set_test_request( "POST /suche HTTP/1.1\r\n\r\n{}" );
+        assert_eq!( api.get_location( &serde_json::json!({"kapitel_id": 4}), "" ), None );
+    }
+
+    #[test]
+    fn if_match() {
+        let mut api = get_api();
+        api.routing_json[ "paths" ][ "kapitel" ][ "delete" ][ "x-version-column" ] = Value::from( "revision_id" );
+        assert_eq!( api.set_test_request( "DELETE /kapitel HTTP/1.1\r\nIf-Match: W/\"3\", \"4\"\r\n\r\n" ).get_if_match(), Some( "W/\"3\", \"4\"" ) );
+        assert_eq!( api.get_if_match_version(), Some( "3".to_string() ) );
+        assert_eq!( api.set_test_request( "DELETE /kapitel HTTP/1.1\r\nIf-Match: *\r\n\r\n" ).get_if_match(), Some( "*" ) );
+        assert_eq!( api.get_if_match_version(), None );
+        assert_eq!( api.set_test_request( "DELETE /kapitel HTTP/1.1\r\nIf-Match: \"h-af63dc4c8601ec8c\"\r\n\r\n" ).get_if_match_version(), None );
+        assert_eq!( api.set_test_request( "POST /kapitel HTTP/1.1\r\nIf-Match: \"3\"\r\n\r\n{}" ).get_if_match(), None );
+    }
+}
+
+#[cfg(test)]
+mod test_replica{
+    use super::*;
+
+    #[test]
+    fn may_use_replica() {
+        let mut api = API::from_routing_json( r#"{"paths": {"suche": {"post": {"operationId": "f", "x-read-only": true}}, 
+            "kapitel": {"post": {"operationId": "kapitel"}}}}"# );
+        assert!( api.set_test_request( "GET /kapitel HTTP/1.1\r\n\r\n" ).may_use_replica( "X-Use-Primary" ) );
+        assert!( !api.set_test_request( "GET /kapitel HTTP/1.1\r\nX-Use-Primary: 1\r\n\r\n" ).may_use_replica( "X-Use-Primary" ) );
+        assert!( !api.set_test_request( "POST /kapitel HTTP/1.1\r\n\r\n{}" ).may_use_replica( "X-Use-Primary" ) );
+        assert!( api.set_test_request( "POST /suche HTTP/1.1\r\n\r\n{}" ).may_use_replica( "X-Use-Primary" ) );
+    }
 }
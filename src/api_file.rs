@@ -0,0 +1,175 @@
+use std::{fs, path::{Path, PathBuf}};
+use serde_json::{Map, Number, Value};
+
+/// `$ref`s are followed at most this deep (guards against cycles)
+const MAX_REF_DEPTH: u8 = 32;
+
+///
+/// Reads the OpenAPI file, JSON or (if the file ends in .yaml or .yml)
+/// YAML. 
+///
+/// `$ref`s to other files, e.g. `schemas/kapitel.yaml#/Kapitel`, are 
+/// resolved relative to the referring file and inlined, so that the 
+/// result is one document in which only local refs (`#/...`) remain.
+pub fn read_api_file( s_path: &str ) -> Result<Value, String>{
+    read_api_files( s_path ).map( |( api, _ )| api )
+}
+
+/// As `read_api_file`, but also returns all files that were read
+/// (the file itself first), e.g. to watch them for changes.
+pub fn read_api_files( s_path: &str ) -> Result<(Value, Vec<PathBuf>), String>{
+    let mut api = read_file( Path::new( s_path ) )?;
+    let mut files = vec![ PathBuf::from( s_path ) ];
+    let dir = Path::new( s_path ).parent().unwrap_or_else( || Path::new( "" ) ).to_path_buf();
+    inline_refs( &mut api, None, &dir, 0, &mut files )
+        .map_err( |e| format!("Cannot resolve $ref in API configuration `{}`: {}", s_path, e) )?;
+    Ok( ( api, files ) )
+}
+
+fn read_file( path: &Path ) -> Result<Value, String>{
+    let s_content = fs::read_to_string( path )
+        .map_err( |e| format!("Cannot open file with API configuration `{}`: {}", path.display(), e) )?;
+    match path.extension().and_then( |e| e.to_str() ){
+        Some( "yaml" ) | Some( "yml" ) => serde_yaml::from_str::<serde_yaml::Value>( &s_content )
+            .map( yaml_to_json ).map_err( |e| e.to_string() ),
+        _ => serde_json::from_str( &s_content ).map_err( |e| e.to_string() )
+    }.map_err( |e| format!("Cannot parse file with API configuration `{}`: {}", path.display(), e) )
+}
+
+/// YAML allows keys that are no strings, e.g. `200:` in
+/// responses; they become strings.
+fn yaml_to_json( yaml: serde_yaml::Value ) -> Value{
+    match yaml{
+        serde_yaml::Value::Null => Value::Null,
+        serde_yaml::Value::Bool( b ) => Value::Bool( b ),
+        serde_yaml::Value::Number( n ) => match ( n.as_i64(), n.as_u64() ){
+            ( Some( i ), _ ) => Value::from( i ),
+            ( _, Some( u ) ) => Value::from( u ),
+            _ => n.as_f64().and_then( Number::from_f64 ).map( Value::Number ).unwrap_or( Value::Null )
+        },
+        serde_yaml::Value::String( s ) => Value::String( s ),
+        serde_yaml::Value::Sequence( seq ) => Value::Array( seq.into_iter().map( yaml_to_json ).collect() ),
+        serde_yaml::Value::Mapping( map ) => {
+            let mut obj = Map::new();
+            for ( key, val ) in map{
+                let s_key = match yaml_to_json( key ){
+                    Value::String( s ) => s,
+                    other => other.to_string()
+                };
+                obj.insert( s_key, yaml_to_json( val ) );
+            }
+            Value::Object( obj )
+        }
+    }
+}
+
+/// Replaces external `$ref`s by what they point to. 
+/// `doc` is the external file that `node` was taken from (None for
+/// the main file): local refs inside external files point into those
+/// files, so they are inlined as well.
+fn inline_refs( node: &mut Value, doc: Option<&Value>, dir: &Path, depth: u8, files: &mut Vec<PathBuf> ) -> Result<(), String>{
+    if depth > MAX_REF_DEPTH {
+        return Err( "$refs nested too deep (circular?)".to_string() );
+    }
+    let s_ref = match node[ "$ref" ].as_str(){
+        Some( r ) if node.is_object() => r.to_string(),
+        _ => {
+            match node{
+                Value::Object( o ) => for val in o.values_mut(){ inline_refs( val, doc, dir, depth, files )?; },
+                Value::Array( a ) => for val in a.iter_mut(){ inline_refs( val, doc, dir, depth, files )?; },
+                _ => {}
+            }
+            return Ok(());
+        }
+    };
+
+    let ( s_file, s_pointer ) = match s_ref.find( '#' ){
+        Some( i ) => ( &s_ref[ ..i ], &s_ref[ i + 1.. ] ),
+        None => ( &s_ref[..], "" )
+    };
+
+    if s_file.is_empty() {
+        // local ref: stays in the main file, is inlined from external files
+        if let Some( d ) = doc {
+            let mut target = d.pointer( s_pointer ).cloned()
+                .ok_or_else( || format!("dangling $ref `{}`", s_ref) )?;
+            inline_refs( &mut target, doc, dir, depth + 1, files )?;
+            *node = target;
+        }
+        return Ok(());
+    }
+
+    let path = dir.join( s_file );
+    let external = read_file( &path )?;
+    if !files.contains( &path ) { files.push( path.clone() ); }
+    let mut target = external.pointer( s_pointer ).cloned()
+        .ok_or_else( || format!("dangling $ref `{}`", s_ref) )?;
+    let external_dir = path.parent().unwrap_or( dir ).to_path_buf();
+    inline_refs( &mut target, Some( &external ), &external_dir, depth + 1, files )?;
+    *node = target;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_read_api_file{
+    use super::*;
+    use std::{env, fs};
+
+    fn write( dir: &Path, s_name: &str, s_content: &str ){
+        let path = dir.join( s_name );
+        fs::create_dir_all( path.parent().unwrap() ).unwrap();
+        fs::write( path, s_content ).unwrap();
+    }
+
+    #[test]
+    fn yaml_with_external_refs() {
+        let dir = env::temp_dir().join( format!("pg_api_muscle_test_{}", std::process::id()) );
+        write( &dir, "api.yaml", r##"
+paths:
+  kapitel:
+    post:
+      operationId: web_api.kapitel
+      requestBody:
+        content:
+          application/json:
+            schema:
+              $ref: "schemas/kapitel.yaml#/Kapitel"
+      responses:
+        200:
+          description: ok
+    get:
+      parameters:
+        - $ref: "#/components/parameters/kapitel_id"
+components:
+  parameters:
+    kapitel_id: {name: kapitel_id}
+"## );
+        write( &dir, "schemas/kapitel.yaml", r##"
+Kapitel:
+  properties:
+    titel: {type: string}
+    buch: {$ref: "#/Id"}
+    editor: {$ref: "common.json#/Id"}
+  required: [titel]
+Id: {type: integer}
+"## );
+        write( &dir, "schemas/common.json", r##"{"Id": {"type": "integer", "format": "int64"}}"## );
+
+        let ( api, files ) = read_api_files( dir.join( "api.yaml" ).to_str().unwrap() ).unwrap();
+        assert_eq!( files.len(), 3 );
+        let schema = &api[ "paths" ][ "kapitel" ][ "post" ][ "requestBody" ][ "content" ][ "application/json" ][ "schema" ];
+        assert_eq!( schema[ "properties" ][ "titel" ][ "type" ], "string" );
+        assert_eq!( schema[ "properties" ][ "buch" ][ "type" ], "integer" );
+        assert_eq!( schema[ "properties" ][ "editor" ][ "format" ], "int64" );
+        assert_eq!( schema[ "required" ][ 0 ], "titel" );
+        assert_eq!( api[ "paths" ][ "kapitel" ][ "post" ][ "responses" ][ "200" ][ "description" ], "ok" );
+        // local refs of the main file stay
+        assert_eq!( api[ "paths" ][ "kapitel" ][ "get" ][ "parameters" ][ 0 ][ "$ref" ], "#/components/parameters/kapitel_id" );
+
+        write( &dir, "loop.json", r##"{"paths": {"a": {"$ref": "loop.json#/paths"}}}"## );
+        assert!( read_api_file( dir.join( "loop.json" ).to_str().unwrap() ).is_err() );
+        write( &dir, "dangling.json", r##"{"paths": {"a": {"$ref": "schemas/common.json#/Nix"}}}"## );
+        assert!( read_api_file( dir.join( "dangling.json" ).to_str().unwrap() ).unwrap_err().contains( "dangling" ) );
+        fs::remove_dir_all( dir ).unwrap();
+    }
+}
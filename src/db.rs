@@ -3,13 +3,32 @@ use crate::API;
 use crate::ParamVal;
 use crate::RequestMethod;
 use crate::Authentication;
+use crate::ReturnPreference;
+use crate::FunctionResult;
+use crate::response::Response;
 use crate::CheckedParam;
 use tokio_postgres::{Client};
-use tokio_postgres::types::ToSql;
+use tokio_postgres::types::{ToSql, Type};
 use log::{error, info};
+use serde_json::Value;
 
 const EMPTY_RESULT: &str = "{}"; // empty string is no JSON
 
+/// Start of the error if the pool has no connection
+/// (exhausted, or database unavailable): Response answers 503.
+pub const ERR_NO_DB_CLIENT: &str = "No db client available";
+
+/// Error if a PATCH or DELETE matched no row: Response answers 404.
+pub const ERR_NO_ROWS: &str = "No rows matched the request";
+
+/// Error if the rows of a PATCH or DELETE are not (any longer) those
+/// in If-Match: Response answers 412.
+pub const ERR_PRECONDITION: &str = "The rows have changed since they were read (If-Match)";
+
+/// Start of the error if the database refuses pg_anon_role: 
+/// Response answers 500.
+pub const ERR_ANON_ROLE: &str = "Error switching to anonymous role";
+
 /// 
 /// Get a JSON result from the database.
 ///
@@ -23,11 +42,12 @@ const EMPTY_RESULT: &str = "{}"; // empty string is no JSON
 /// usually the first row contains all 
 /// information in an array).
 ///
-/// query_execute
+/// get_aggregate
 /// --------------
-/// Executes an SQL command (update, delete,
-/// insert) with parameters and delivers
-/// the count of rows affected.
+/// Retrieves the rows affected by an update
+/// or delete (or an insert with `Prefer:
+/// return=representation`) as JSON array,
+/// together with their count.
 ///
 /// exe_count_limit
 /// --------------
@@ -35,58 +55,201 @@ const EMPTY_RESULT: &str = "{}"; // empty string is no JSON
 /// insert, can limit rows, which is 
 /// currently under construction, though
 ///
-pub async fn get_db_response( pool: &Pool, api: &mut API ) -> Result<String, String>{
-   let http_method = api.request.method;         
-   let needs_auth = api.request.api_needs_auth == Authentication::NEEDED; // JWT Token needed?
-
+pub async fn get_db_response( pool: &Pool, api: &mut API ) -> Result<Vec<u8>, String>{
    let mut client = match pool.get().await{
        Ok (cl) => cl,
-       Err( e ) => {return Err(format!("No db client available: {:?}", e)); }
+       Err( e ) => {return Err(format!("{}: {:?}", ERR_NO_DB_CLIENT, e)); }
    };
 
-   // #HACK Timezone 
-   adjust_timezone( &mut client, "Europe/Berlin" ).await;
+   // to cancel the query if the client disconnects (see main)
+   *api.cancel_token.lock().unwrap() = Some( client.cancel_token() );
+
+   let in_transaction = set_request_context( &mut client, api, false ).await?;
+   get_statement_response( &mut client, api, in_transaction ).await
+}
+
+///
+/// Batch requests (see Response::handle_batch) run their operations 
+/// in one transaction on one connection: begin_batch, then 
+/// get_db_batch_operation_response for each operation, and end_batch.
+///
+pub async fn begin_batch( pool: &Pool, api: &mut API ) -> Result<deadpool_postgres::Client, String>{
+   let client = match pool.get().await{
+       Ok (cl) => cl,
+       Err( e ) => {return Err(format!("{}: {:?}", ERR_NO_DB_CLIENT, e)); }
+   };
+   *api.cancel_token.lock().unwrap() = Some( client.cancel_token() );
+   match client.batch_execute( "BEGIN;" ).await{
+       Ok( _ ) => Ok( client ),
+       Err( e ) => Err( format!("Error beginning transaction: `{}`", e) )
+   }
+}
+
+/// Result of the operation of a batch that is the request of `api` 
+/// now. Its role, statement timeout, token and claims are reset 
+/// afterwards, so that the next operation does not see them.
+pub async fn get_db_batch_operation_response( client: &mut Client, api: &mut API ) -> Result<String, String>{
+   *api.cancel_token.lock().unwrap() = Some( client.cancel_token() );
+   set_request_context( client, api, true ).await?;
+   let content = get_statement_response( client, api, false ).await?;
+   if let Err( e ) = client.batch_execute( &get_reset_request_context_sql( api ) ).await{
+       return Err( format!("Database could not complete the request: `{}`", e) );
+   }
+
+   // the results of a batch are an array: raw results are strings in it
+   match api.get_function_result(){
+       FunctionResult::RAW => Ok( Value::String( String::from_utf8_lossy( &content ).to_string() ).to_string() ),
+       _ => Ok( String::from_utf8_lossy( &content ).to_string() )
+   }
+}
+
+/// Undoes set_request_context within a transaction: the local settings
+/// (token and claims) are emptied, as SET LOCAL cannot be reset
+fn get_reset_request_context_sql( api: &mut API ) -> String{
+   let mut sql = "RESET ROLE; SET LOCAL statement_timeout TO DEFAULT;".to_string();
+   if api.request.api_needs_auth == Authentication::NEEDED{
+       let mut keys = vec![ api.get_pg_token_name().to_string() ];
+       keys.extend( api.pg_set_keys.iter().cloned() );
+       let s_configs = keys.iter().map( |k| format!("set_config('{}', '', true)", k) ).collect::<Vec<String>>().join(", ");
+       sql = format!("{} SELECT {};", sql, s_configs);
+   }
+   sql
+}
+
+/// Commit (or roll back) the operations of a batch
+pub async fn end_batch( client: &mut Client, b_commit: bool ) -> Result<(), String>{
+   match b_commit{
+       true => client.batch_execute( "COMMIT;" ).await.map_err( |e| format!("Database could not complete the request: `{}`", e) ),
+       false => { rollback( client ).await; Ok(()) }
+   }
+}
+
+/// Prepares the connection for this request: token and claims 
+/// (set_auth) or anonymous role, statement timeout, and a transaction
+/// if needed (SET LOCAL, x-max-affected-rows, If-Match). Returns 
+/// whether a transaction was begun (unless `b_in_transaction` already).
+async fn set_request_context( client: &mut Client, api: &mut API, b_in_transaction: bool ) -> Result<bool, String>{
+   let needs_auth = api.request.api_needs_auth == Authentication::NEEDED; // JWT Token needed?
+   let anon_role = if needs_auth { None } else { api.get_pg_anon_role().map( |r| r.to_string() ) };
 
    if needs_auth{
-       set_auth( &mut client, &api.get_pg_token_name().clone(), &api.request.get_auth(), &api.pg_set ).await;
+       set_auth( client, &api.get_pg_token_name().clone(), &api.request.get_auth(), &api.pg_set, !b_in_transaction ).await;
+   }
+
+   // Requests without authentication run as the
+   // anonymous role, if one is configured
+   if let Some( role ) = &anon_role{
+       set_anon_role( client, role, !b_in_transaction ).await?;
+   }
+   let mut in_transaction = needs_auth || anon_role.is_some();
+
+   // Slow queries are cancelled after x-statement-timeout 
+   // (SET LOCAL needs a transaction, which may have to be begun)
+   if let Some( s_timeout ) = api.get_statement_timeout(){
+       if let Err( e ) = set_statement_timeout( client, &s_timeout, !in_transaction && !b_in_transaction ).await{
+           if in_transaction && !b_in_transaction { unset_auth( client ).await; }
+           return Err( e );
+       }
+       in_transaction = true;
+   }
+
+   // x-max-affected-rows (too many changes are rolled back)
+   // and If-Match need a transaction
+   if ( api.get_max_affected_rows().is_some() || api.get_if_match().is_some() ) && !in_transaction && !b_in_transaction {
+       if let Err( e ) = client.batch_execute( "BEGIN;" ).await{ return Err( format!("Error beginning transaction: `{}`", e) ); }
+       in_transaction = true;
+   }
+   Ok( in_transaction && !b_in_transaction )
+}
+
+/// Runs the statement of this request on a prepared connection (see
+/// set_request_context) and ends the transaction if `end_transaction`.
+async fn get_statement_response( client: &mut Client, api: &mut API, end_transaction: bool ) -> Result<Vec<u8>, String>{
+   let http_method = api.request.method;         
+   let max_affected_rows = api.get_max_affected_rows();
+   let selection = get_selection( api );
+   let if_match = api.get_if_match().map( |s| s.to_string() );
+
+   // If-Match: with x-version-column, the version is part of the
+   // where criteria (see add_version_criterion). Otherwise, the ETag 
+   // is a hash of what GET returns, so the rows are selected (and 
+   // locked) like GET does.
+   let version = api.get_if_match_version().map( ParamVal::Text );
+   if let Some( s_if_match ) = if_match.as_ref().filter( |_| version.is_none() ){
+       let s_current = match is_table( client, &api.get_operations_id() ).await{
+           Ok( b_lock ) => get_first_row( client, &get_db_locking_get_sql( api, b_lock ), Some( &api.get_checked_query_param_vals() ) ).await,
+           Err( e ) => Err( e )
+       };
+       let s_current = match s_current{
+           Ok( r ) => r,
+           Err( e ) => {
+               if end_transaction { unset_auth( client ).await; }
+               error!("DB failure: `{}`", e);
+               return Err( format!("Database could not complete the request: `{}`", e) );
+           }
+       };
+       if s_current == EMPTY_RESULT || !Response::etag_matches( s_if_match, &Response::get_hash_etag( s_current.as_bytes() ) ){
+           if end_transaction { unset_auth( client ).await; }
+           return Err( ERR_PRECONDITION.to_string() );
+       }
    }
 
+   // x-body-as-single-argument: the (checked) properties of the request body
+   let body_val = api.get_body_argument().is_some().then( || ParamVal::Text( api.get_body_argument_value() ) );
+
    // -------------------------------------------------------------------------------- 
    // Matching HTTP methods:
    // GET => Select
    // POST => Insert into or Select
    // DELETE => Delete
    // PATCH => Update ... Where
-   match http_method{
+   let result = match http_method{
        // ---------------------------------------- 
        // GET
        RequestMethod::GET => {
            let sql = get_db_get_sql( api );
-           query_db( &mut client, needs_auth, &api.get_checked_query_param_vals(), &sql, http_method).await
+           query_db( client, end_transaction, &api.get_checked_query_param_vals(), &sql, http_method, None, selection).await
        },
 
        // ---------------------------------------- 
        // DELETE
        RequestMethod::DELETE => {
            let sql = get_db_delete_sql( api );
-           query_db( &mut client, needs_auth, &api.get_checked_query_param_vals(), &sql, http_method).await
+           let mut vals = api.get_checked_query_param_vals();
+           if let Some( v ) = &version { vals.push( v ); }
+           query_db( client, end_transaction, &vals, &sql, http_method, max_affected_rows, selection).await
        },
 
        // ---------------------------------------- 
        // POST
        RequestMethod::POST => {
            let sql = get_db_post_sql( api );
-           query_db( &mut client, needs_auth, &api.get_checked_post_param_vals(), &sql, http_method).await
+           let vals = match &body_val{
+               Some( v ) => vec![v],
+               None => api.get_checked_post_param_vals()
+           };
+           query_db( client, end_transaction, &vals, &sql, http_method, None, selection).await
       },
 
       // ---------------------------------------- 
       // PATCH
        RequestMethod::PATCH => {
            let sql = get_db_patch_sql( api );
-           query_db( &mut client, needs_auth, &api.get_checked_combined_param_vals(), &sql, http_method).await
+           let mut vals = match &body_val{
+               Some( v ) => vec![v],
+               None => api.get_checked_combined_param_vals()
+           };
+           if let Some( v ) = &version { vals.push( v ); }
+           query_db( client, end_transaction, &vals, &sql, http_method, max_affected_rows, selection).await
        },
 
        _ => Err( "Methode nicht implementiert".to_string() )
+   };
+
+   // with If-Match, rows that are not found have changed
+   match result{
+       Err( e ) if e == ERR_NO_ROWS && if_match.is_some() => Err( ERR_PRECONDITION.to_string() ),
+       r => r
    }
 }
 
@@ -96,9 +259,12 @@ pub async fn get_db_response( pool: &Pool, api: &mut API ) -> Result<String, Str
 /// query_parameters: values corresponding to $1, $2, ..., $n
 /// s_sql: SQL command with $1, $2, ..., $n
 /// method: HTTP-Request method of this request.
+/// max_affected_rows: roll back if more rows are affected (needs a transaction)
+/// selection: what s_sql selects (see Selection); with the count of rows,
+///     no rows are an error for PATCH and DELETE (ERR_NO_ROWS)
 ///
 /// OUT:
-/// returns: JSON response from DB, or Error (String).
+/// returns: JSON response from DB (or raw, see get_raw_row), or Error (String).
 ///
 /// LOGS:
 /// error: "EB failure" + error information
@@ -107,29 +273,55 @@ async fn query_db(
     clean_auth_after_query: bool,
     query_parameters: &Vec<&ParamVal>,
     sql: &str,
-    method: RequestMethod) -> Result<String, String>{
-
-    // query method is query_execute_string (for DELETE) or get_first_row (for 
-    // INSERT, UPDATE, SELECT).
-    if method != RequestMethod::DELETE {
-        match get_first_row( client, &sql, Some( &query_parameters ) ).await{
-            Ok( r ) => {if clean_auth_after_query {unset_auth( client ).await;} Ok(r)},
-            Err( e ) => {if clean_auth_after_query {unset_auth( client ).await;}
-                error!("DB failure: `{}`", e);
-                Err( format!("Database could not complete the request: `{}`", e))
-            }
-        }
-    }else{
-        match query_execute_string( client, &sql, Some( &query_parameters ) ).await{
-            Ok( r ) => {if clean_auth_after_query {unset_auth( client ).await;} Ok(r)},
-            Err( e ) => {if clean_auth_after_query {unset_auth( client ).await;}
-                error!("DB failure: `{}`", e);
-                Err( format!("Database could not complete the request: `{}`", e))
-            }
+    method: RequestMethod,
+    max_affected_rows: Option<u64>,
+    selection: Selection) -> Result<Vec<u8>, String>{
+
+    // query method is get_aggregate (for UPDATE, DELETE), get_raw_row 
+    // (x-returns: raw) or get_first_row (for INSERT, SELECT).
+    let result = match selection{
+        Selection::Counted => get_aggregate( client, sql, Some( query_parameters ) ).await.map( |( r, n )| ( r.into_bytes(), n ) ),
+        Selection::Raw => get_raw_row( client, sql, Some( query_parameters ) ).await.map( |r| ( r, 0 ) ),
+        Selection::First => get_first_row( client, sql, Some( query_parameters ) ).await.map( |r| ( r.into_bytes(), 0 ) )
+    };
+
+    match result{
+        Ok( ( _, 0 ) ) if selection == Selection::Counted && method != RequestMethod::POST => {
+            if clean_auth_after_query {unset_auth( client ).await;}
+            Err( ERR_NO_ROWS.to_string() )
+        },
+        Ok( ( _, n ) ) if max_affected_rows.is_some_and( |max| n > max ) => {
+            rollback( client ).await;
+            error!("Rolled back: {} rows affected, x-max-affected-rows is {}", n, max_affected_rows.unwrap());
+            Err( format!("The request would change {} rows, more than the {} allowed; nothing was changed", n, max_affected_rows.unwrap()) )
+        },
+        Ok( ( r, _ ) ) => {if clean_auth_after_query {unset_auth( client ).await;} Ok(r)},
+        Err( e ) => {if clean_auth_after_query {unset_auth( client ).await;}
+            error!("DB failure: `{}`", e);
+            Err( format!("Database could not complete the request: `{}`", e))
         }
     }
 }
 
+/// Claims for an API key as returned by the postgres function
+/// configured as `api_key_pg_function`, which is called as
+/// `select <function>($1)::text` and returns json (or null for 
+/// an invalid key).
+pub async fn get_api_key_claims( pool: &Pool, s_function: &str, s_key: &str ) -> Option<Value>{
+    let client = match pool.get().await{
+        Ok (cl) => cl,
+        Err( e ) => { error!("No db client available to check API key: {:?}", e); return None; }
+    };
+    match client.query_opt( &format!("select {}($1)::text;", s_function)[..], &[&s_key] ).await{
+        Ok( Some( row ) ) => {
+            let s_claims: Option<String> = row.get(0);
+            s_claims.and_then( |c| serde_json::from_str( &c ).ok() )
+        },
+        Ok( None ) => None,
+        Err( e ) => { error!("Checking API key through `{}` failed: {}", s_function, e); None }
+    }
+}
+
 // Build SQL String for a patch request -> update ...
 fn get_db_patch_sql( api: &mut API ) -> String{
         let query = &api.get_operations_id();    // The query
@@ -140,18 +332,21 @@ fn get_db_patch_sql( api: &mut API ) -> String{
        // want 'select' rather than 'insert into' or 'update'.
        match api.request.method_reroute {
            RequestMethod::POSTorPATCHasGET => {
-               format!("select json_agg(t)::text from (select * from {} ({})) t;", 
-                   query, 
-                   get_sql_named_notation_from_params( &api.get_checked_post_params( ) ))
+               let s_args = get_function_arguments_sql( api );
+               get_function_call_sql( api, query, &s_args )
            },
-           _ => match api.get_checked_query_params().len(){
-                0 => format!("update {} set {} returning row_to_json({}.*)::text;", query, 
-                        get_parameter_assignment_csv( &api.get_checked_post_params( ) ), query),
-                _ => format!("update {} set {} where ({}) returning row_to_json({}.*)::text;", 
-                        query, 
-                        get_parameter_assignment_csv( &api.get_checked_post_params( ) ), 
-                        get_parameter_where_criteria( api ), 
-                        query)
+           _ => { 
+               let n_params = api.get_checked_post_params().len() + api.get_checked_query_params().len();
+               let s_where = get_parameter_where_criteria( api );
+               let s_update = match &add_version_criterion( api, s_where, n_params + 1 )[..]{
+                    "" => format!("update {} set {}", query, 
+                            get_parameter_assignment_csv( &api.get_checked_post_params( ) )),
+                    s_where => format!("update {} set {} where ({})", 
+                            query, 
+                            get_parameter_assignment_csv( &api.get_checked_post_params( ) ), 
+                            s_where)
+               };
+               get_returning_sql( api, &s_update, query )
            }
         }
 }
@@ -172,31 +367,71 @@ fn get_db_post_sql( api: &mut API ) -> String{
        // want 'select' rather than 'insert into' or 'update'.
        match api.request.method_reroute {
            RequestMethod::POSTorPATCHasGET => {
-               format!("select json_agg(t)::text from (select * from {} ({})) t;", 
-                   query, 
-                   get_sql_named_notation_from_params( &api.get_checked_post_params( ) ))
+               let s_args = get_function_arguments_sql( api );
+               get_function_call_sql( api, query, &s_args )
            },
 
            // Default for POST is 'insert into,' though.
-           _ =>{ format!("insert into {} ({}) values ({}) returning row_to_json({}.*)::text;", 
-               query, 
-               get_parameter_names_csv( &api.get_checked_post_params() ), 
-               get_parameter_placeholder_csv( &api.get_checked_post_params(), None ), 
-               query)
+           _ =>{ 
+               let s_insert = format!("insert into {} ({}) values ({})", 
+                   query, 
+                   get_parameter_names_csv( &api.get_checked_post_params() ), 
+                   get_parameter_placeholder_csv( &api.get_checked_post_params(), None ));
+               get_returning_sql( api, &s_insert, query )
            }
        }
 }
 
+/// Arguments of a function called with the request body: one per
+/// property (named notation), or the whole body as the one argument
+/// of `x-body-as-single-argument` (bound in get_statement_response)
+fn get_function_arguments_sql( api: &mut API ) -> String{
+    match api.get_body_argument(){
+        Some( s_arg ) => format!("\"{}\"=>$1::text::jsonb", s_arg),
+        None => get_sql_named_notation_from_params( api.get_checked_post_params( ) )
+    }
+}
+
+/// Calls function `query` and selects its result as `x-returns` 
+/// says: by default all rows as JSON array. A scalar or single object
+/// is JSON null if there is no row. A `raw` result is selected as it
+/// is and as text (see get_raw_row).
+fn get_function_call_sql( api: &API, query: &str, s_args: &str ) -> String{
+    match api.get_function_result(){
+        FunctionResult::ARRAY => format!("select json_agg(t)::text from (select * from {} ({})) t;", query, s_args),
+        FunctionResult::SINGLEOBJECT => format!("select coalesce((select row_to_json(t) from (select * from {} ({})) t limit 1), 'null')::text;", query, s_args),
+        FunctionResult::SCALAR => format!("select coalesce((select to_json(t.r) from {} ({}) t(r) limit 1), 'null')::text;", query, s_args),
+        FunctionResult::RAW => format!("select t.r, t.r::text from {} ({}) t(r) limit 1;", query, s_args)
+    }
+}
+
 /// Build SQL String for a get request -> select * from
 fn get_db_get_sql( api: &mut API ) -> String{
 
     let query = &api.get_operations_id( );    // The query
 
+    // x-function: query parameters are the function's arguments
+    if api.is_function_call() {
+        let s_args = get_sql_named_notation_from_params( api.get_checked_query_params( ) );
+        return get_function_call_sql( api, query, &s_args );
+    }
+
     // Case 0 means: there are no parameters.
+    let s_order = get_order_by( api );
     match api.get_checked_query_params().len(){
-        0 => format!("select json_agg(t)::text from (select * from {}) t;", query),
-        _ => format!("select json_agg(t)::text from (select * from {} where {}) t;", 
-            query, get_parameter_where_criteria( api ))
+        0 => format!("select json_agg(t)::text from (select * from {}{}) t;", query, s_order),
+        _ => format!("select json_agg(t)::text from (select * from {} where {}{}) t;", 
+            query, get_parameter_where_criteria( api ), s_order)
+    }
+}
+
+/// ` order by` the primary key of the route (see API::get_order_key), 
+/// or nothing
+fn get_order_by( api: &API ) -> String{
+    let key = api.get_order_key();
+    match key.is_empty(){
+        true => String::new(),
+        false => format!(" order by {}", key.iter().map( |k| format!("\"{}\"", k) ).collect::<Vec<String>>().join( ", " ))
     }
 }
 
@@ -205,10 +440,84 @@ fn get_db_delete_sql( api: &mut API ) -> String{
 
        let query = &api.get_operations_id( );    // The query
 
-        match api.get_checked_query_params().len(){
-            0 => format!("delete from {};", query),
-            _ => format!("delete from {} where {};", query, get_parameter_where_criteria( api ))
-        }
+        let n_params = api.get_checked_query_params().len();
+        let s_where = get_parameter_where_criteria( api );
+        let s_delete = match &add_version_criterion( api, s_where, n_params + 1 )[..]{
+            "" => format!("delete from {}", query),
+            s_where => format!("delete from {} where {}", query, s_where)
+        };
+        get_returning_sql( api, &s_delete, query )
+}
+
+/// Adds the version of the rows that the client has seen (If-Match,
+/// compared with the x-version-column in its JSON format, see
+/// Response::get_version_etag) as parameter $n to the where criteria.
+fn add_version_criterion( api: &API, s_where: String, n: usize ) -> String{
+    let s_column = match ( api.get_version_column(), api.get_if_match_version() ){
+        ( Some( s_column ), Some( _ ) ) => s_column,
+        _ => return s_where
+    };
+    let s_criterion = format!("to_json(\"{}\")#>>'{{}}' = ${}", s_column, n);
+    match s_where.trim_end(){
+        "" => s_criterion,
+        s_where => format!("{} and {}", s_where, s_criterion)
+    }
+}
+
+/// SQL of the GET request with the same parameters (for If-Match),
+/// which locks the rows until they are updated or deleted if `b_lock`
+/// (views with aggregates etc. cannot be locked, see is_table)
+fn get_db_locking_get_sql( api: &mut API, b_lock: bool ) -> String{
+    let query = &api.get_operations_id( );
+    let s_lock = format!("{}{}", get_order_by( api ), if b_lock { " for update" } else { "" });
+    match api.get_checked_query_params().len(){
+        0 => format!("select json_agg(t)::text from (select * from {}{}) t;", query, s_lock),
+        _ => format!("select json_agg(t)::text from (select * from {} where {}{}) t;", 
+            query, get_where_criteria_numbered( api.get_checked_query_params(), 0 ), s_lock)
+    }
+}
+
+/// Is the relation a table (rather than a view)?
+async fn is_table( client: &mut Client, s_relation: &str ) -> Result<bool, tokio_postgres::Error>{
+    let row = client.query_one( "select coalesce((select relkind in ('r', 'p') from pg_class where oid = to_regclass($1)), false);", 
+        &[&s_relation] ).await?;
+    Ok( row.get( 0 ) )
+}
+
+/// Completes an insert, update or delete statement: updates and
+/// deletes -- and inserts with the preference `return=representation`
+/// -- select all affected rows as JSON array and their count (see 
+/// get_aggregate); other inserts return the row.
+fn get_returning_sql( api: &API, s_statement: &str, query: &str ) -> String{
+    match is_counted( api ){
+        true => format!("with t as ({} returning *) select coalesce(json_agg(t), '[]')::text, count(*) from t;", s_statement),
+        false => format!("{} returning row_to_json({}.*)::text;", s_statement, query)
+    }
+}
+
+/// What the SQL of a request selects (see query_db)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Selection{
+    First,      // the first row: a JSON aggregate or a row
+    Counted,    // a JSON array and the count of rows (see get_returning_sql)
+    Raw         // a value as it is, and as text (see get_raw_row)
+}
+
+fn get_selection( api: &API ) -> Selection{
+    if is_counted( api ) { return Selection::Counted; }
+    match api.get_function_result(){
+        FunctionResult::RAW => Selection::Raw,
+        _ => Selection::First
+    }
+}
+
+/// Does the SQL of this request count the rows it affects? (see get_returning_sql)
+fn is_counted( api: &API ) -> bool{
+    match api.request.method{
+        RequestMethod::PATCH => api.request.method_reroute != RequestMethod::POSTorPATCHasGET,
+        RequestMethod::DELETE => true,
+        _ => api.get_return_preference() == ReturnPreference::REPRESENTATION
+    }
 }
 
 // ==================================================================================
@@ -272,7 +581,7 @@ fn get_parameter_assignment_csv( request: &Vec<CheckedParam>  ) -> String{
 /// id=$3 and salary=$4 (as in: update X set company=$1 and year=$2 where id=$3 and "salary"=$4.
 fn get_parameter_where_criteria( api: &mut API ) -> String{
 
-    let mut ii = match api.request.method{
+    let ii = match api.request.method{
         RequestMethod::PATCH => api.get_checked_post_params().len(),
         _ => {
             if api.get_checked_post_params().len() > 0{
@@ -282,7 +591,12 @@ fn get_parameter_where_criteria( api: &mut API ) -> String{
         }
     }; 
 
-    api.get_checked_query_params().into_iter().map( |y| { 
+    get_where_criteria_numbered( api.get_checked_query_params(), ii )
+}
+
+/// `and`-separated criteria of get_parameter_where_criteria, numbered from $(ii+1)
+fn get_where_criteria_numbered( params: &[CheckedParam], mut ii: usize ) -> String{
+    params.iter().map( |y| { 
         ii+=1;
         format!("and \"{}\"{}${} ", &y.name, &y.relation, ii)}  ).collect::<String>().chars().skip(4).collect()
 }
@@ -306,12 +620,9 @@ fn get_parameter_placeholder_csv( parms: &Vec<CheckedParam>, start_arg: Option<u
 // DB Interaction
 //
 /**
- * @todo: verwendet query_opt (anstatt query_one), da bei PATCH sonst eine leere Antwort Probleme
- * macht. 
- *
- * Problem immer noch: Ein PATCH mit falschen Auth Bearer wird u.U. nur durch eine leere Antwort
- * (aber HTTP Status 200) quittiert. Man kann sich streiten, ob das nicht besser ein Fehler wäre.
- * Andererseits kann das vermutlich die SQL Fkt. leisten?
+ * Erste Zeile des Ergebnisses (query_opt). PATCH und DELETE verwenden stattdessen 
+ * get_aggregate: alle betroffenen Zeilen, und ERR_NO_ROWS (404), wenn keine 
+ * betroffen ist -- z.B. bei falscher ID oder fehlender Berechtigung.
  *
  * @todo: leere Antwort gibt "{}" zurück -- konfigurierbar, ob JSON Antwort oder Txt?
  **/
@@ -335,15 +646,27 @@ async fn get_first_row(client: &mut Client, s_sql: &str, prep_vals_opt: Option<&
     }
 }
 
-/// executes the SQL and returns {"message":"rows affected: <nor>"}, with nor = number of rows
-/// affected.
-async fn query_execute_string(client: &mut Client, s_sql: &str, prep_vals: Option< &Vec::<&ParamVal>> ) -> Result<String, tokio_postgres::Error>{
-    match client.execute( s_sql, &get_pg_parameter_vector(  prep_vals )).await{
-        Ok( e ) => Ok( format!("{{\"message\":\"rows affected: {}\"}}", e ) ),
-        Err( f ) => Err( f )
+/// The first value of a query like `select t.r, t.r::text from ...` 
+/// as it is: the bytes of a bytea, otherwise the text. Empty if there 
+/// is no row or the value is null.
+async fn get_raw_row(client: &mut Client, s_sql: &str, prep_vals_opt: Option<&Vec::<&ParamVal>>) -> Result<Vec<u8>, tokio_postgres::Error>{ 
+    let row = match client.query_opt( s_sql, &get_pg_parameter_vector( prep_vals_opt )).await?{
+        Some( row ) => row,
+        None => return Ok( vec![] )
+    };
+    match row.columns()[0].type_() == &Type::BYTEA{
+        true => Ok( row.get::<_, Option<Vec<u8>>>( 0 ).unwrap_or_default() ),
+        false => Ok( row.get::<_, Option<String>>( 1 ).unwrap_or_default().into_bytes() )
     }
 }
 
+/// The result and count of a query that selects both, like
+/// `select json_agg(t)::text, count(*) from t`
+async fn get_aggregate(client: &mut Client, s_sql: &str, prep_vals_opt: Option<&Vec::<&ParamVal>>) -> Result<(String, u64), tokio_postgres::Error>{ 
+    let row = client.query_one( s_sql, &get_pg_parameter_vector( prep_vals_opt )).await?;
+    Ok( ( row.get( 0 ), row.get::<_, i64>( 1 ) as u64 ) )
+}
+
 /**
  * Finish the local transaction esp. to invalidate
  * the config parameter `request.pg_api_muscle.token='TOKEN'` 
@@ -355,6 +678,43 @@ async fn unset_auth( client: &mut Client ){
     }
 }
 
+/**
+ * Undo the local transaction (instead of `unset_auth`)
+ **/
+async fn rollback( client: &mut Client ){
+    if let Err( e ) = client.batch_execute( "ROLLBACK;" ).await{
+        error!("Rollback FAIL: {}", e);
+    }
+}
+
+/**
+ * Limit the time this request's statements may take. SET LOCAL only 
+ * works in a transaction, which is begun unless `set_auth` or 
+ * `set_anon_role` did already. `unset_auth` ends it.
+ **/
+async fn set_statement_timeout( client: &mut Client, s_timeout: &str, b_begin: bool ) -> Result<(), String>{
+    match client.batch_execute( &format!("{}SET LOCAL statement_timeout = '{}';", if b_begin {"BEGIN; "} else {""}, s_timeout )[..] ).await{
+        Ok( _ ) => Ok(()),
+        Err( e ) => Err( format!("Error setting statement timeout `{}`: `{}`", s_timeout, e) ) 
+    }
+}
+
+/**
+ * Switch to the role configured as `pg_anon_role` for requests that
+ * need no authentication. Like `set_auth`, this opens a transaction 
+ * that `unset_auth` ends.
+ **/
+async fn set_anon_role( client: &mut Client, s_role: &str, b_begin: bool ) -> Result<(), String>{
+    match client.batch_execute( &format!("{}SET LOCAL ROLE \"{}\";", if b_begin {"BEGIN; "} else {""}, s_role )[..] ).await{
+        Ok( _ ) => Ok(()),
+        Err( e ) => {
+            if b_begin { rollback( client ).await; }
+            error!("{} `{}`: `{}`", ERR_ANON_ROLE, s_role, e);
+            Err( format!("{} `{}`", ERR_ANON_ROLE, s_role) ) 
+        }
+    }
+}
+
 /**
  * Set local config parameter `request.pg_api_muscle.token='TOKEN'` in tokio_postgres.
  * A transaction in client would have looked better, but tests showed 
@@ -363,8 +723,8 @@ async fn unset_auth( client: &mut Client ){
  *
  * token name is now configured in the .env file
  **/
-async fn set_auth( client: &mut Client, s_token_name: &str, s_auth: &str, s_pg_set: &str ){
-    match client.batch_execute( &format!("BEGIN; SET LOCAL {}='{}';{};", s_token_name, s_auth, s_pg_set )[..] ).await{
+async fn set_auth( client: &mut Client, s_token_name: &str, s_auth: &str, s_pg_set: &str, b_begin: bool ){
+    match client.batch_execute( &format!("{}SET LOCAL {}='{}';{};", if b_begin {"BEGIN; "} else {""}, s_token_name, s_auth, s_pg_set )[..] ).await{
         Ok( _ ) => {},
         Err( e ) => { panic!("Error transferring the auth token to the database: `{}`", e); }
     };
@@ -393,18 +753,6 @@ fn get_to_sql_from_param_val( par: &ParamVal ) -> &(dyn ToSql + Sync){
     }
 }
 
-/**
- *  https://github.com/sfackler/rust-tokio_postgres/issues/147
- **/
-pub async fn adjust_timezone( mut client: &mut Client, tz: &str) {
-    info!("Db-init, tz: initial timezone: {}", get_first_row( &mut client, "show timezone", None ).await.unwrap());
-    info!("Db-init, tz: initzal time: {}", get_first_row( &mut client, "select now()::text;", None).await.unwrap());
-    info!("Db-init, tz: set tz to {}: {}", tz, client.execute( &format!("set timezone='{}';", tz)[..], &[] ).await.unwrap());
-    info!("Db-init, tz: current timezone: {}", get_first_row( &mut client, "show timezone" ,None).await.unwrap());
-    info!("Db-init, tz: current db time: {}", get_first_row( &mut client, "select now()::text;",None ).await.unwrap());
-
-}
-
 #[cfg(test)]
 mod test_get_named_notation{
     use super::*;
@@ -431,6 +779,73 @@ mod test_get_named_notation{
     }
 }
 
+#[cfg(test)]
+mod test_get_returning_sql{
+    use super::*;
+
+    #[test]
+    fn returning() {
+        let mut api = API::from_routing_json( "{}" );
+        let s_counted = "with t as (delete from k returning *) select coalesce(json_agg(t), '[]')::text, count(*) from t;";
+        assert_eq!( get_returning_sql( api.set_test_request( "DELETE /k HTTP/1.1\r\n\r\n" ), "delete from k", "k" ), s_counted );
+        assert_eq!( get_returning_sql( api.set_test_request( "PATCH /k HTTP/1.1\r\n\r\n{}" ), "update k set a=$1", "k" ), 
+            "with t as (update k set a=$1 returning *) select coalesce(json_agg(t), '[]')::text, count(*) from t;" );
+        assert_eq!( get_returning_sql( api.set_test_request( "POST /k HTTP/1.1\r\n\r\n{}" ), "insert into k (a) values ($1)", "k" ), 
+            "insert into k (a) values ($1) returning row_to_json(k.*)::text;" );
+        assert_eq!( get_returning_sql( api.set_test_request( "POST /k HTTP/1.1\r\nPrefer: return=representation\r\n\r\n{}" ), "insert into k (a) values ($1)", "k" ), 
+            "with t as (insert into k (a) values ($1) returning *) select coalesce(json_agg(t), '[]')::text, count(*) from t;" );
+    }
+}
+
+#[cfg(test)]
+mod test_reset_request_context_sql{
+    use super::*;
+
+    #[test]
+    fn reset() {
+        let mut api = API::from_routing_json( r#"{"paths": {"kapitel": {"delete": {"x-auth-method": "forward_jwt_bearer"}}, "alt": {"delete": {}}}}"# );
+        api.set_test_request( "DELETE /kapitel HTTP/1.1\r\nAuthorization: Bearer abc\r\n\r\n" );
+        api.pg_set_keys = vec![ "pg_api_muscle.editor_id".to_string() ];
+        assert_eq!( get_reset_request_context_sql( &mut api ), "RESET ROLE; SET LOCAL statement_timeout TO DEFAULT; \
+            SELECT set_config('request.pg_api_muscle.token', '', true), set_config('pg_api_muscle.editor_id', '', true);" );
+        assert_eq!( get_reset_request_context_sql( api.set_test_request( "DELETE /alt HTTP/1.1\r\n\r\n" ) ), 
+            "RESET ROLE; SET LOCAL statement_timeout TO DEFAULT;" );
+    }
+}
+
+#[cfg(test)]
+mod test_get_db_locking_get_sql{
+    use super::*;
+
+    #[test]
+    fn order_and_lock() {
+        let mut api = API::from_routing_json( r#"{"paths": {"kapitel": {"get": {"operationId": "web_api.kapitel"}, 
+            "delete": {"operationId": "web_api.kapitel"}, "post": {"operationId": "web_api.kapitel", "x-primary-key": ["kapitel_id"]}},
+            "buch": {"delete": {"operationId": "web_api.buch"}}}}"# );
+        assert_eq!( get_db_locking_get_sql( api.set_test_request( "DELETE /kapitel HTTP/1.1\r\n\r\n" ), true ), 
+            "select json_agg(t)::text from (select * from web_api.kapitel order by \"kapitel_id\" for update) t;" );
+        assert_eq!( get_db_get_sql( api.set_test_request( "GET /kapitel HTTP/1.1\r\n\r\n" ) ), 
+            "select json_agg(t)::text from (select * from web_api.kapitel order by \"kapitel_id\") t;" );
+        assert_eq!( get_db_locking_get_sql( api.set_test_request( "DELETE /buch HTTP/1.1\r\n\r\n" ), false ), 
+            "select json_agg(t)::text from (select * from web_api.buch) t;" );
+    }
+}
+
+#[cfg(test)]
+mod test_get_function_call_sql{
+    use super::*;
+
+    #[test]
+    fn function_result() {
+        let mut api = API::from_routing_json( r#"{"paths": {"zahl": {"get": {"x-function": true, "x-returns": "scalar"}},
+            "seite": {"get": {"x-function": true, "x-returns": "single-object"}}, "alle": {"get": {"x-function": true}}}}"# );
+        assert_eq!( get_function_call_sql( api.set_test_request( "GET /zahl HTTP/1.1\r\n\r\n" ), "f", "a=>$1" ), "select coalesce((select to_json(t.r) from f (a=>$1) t(r) limit 1), 'null')::text;" );
+        assert_eq!( get_function_call_sql( api.set_test_request( "GET /seite HTTP/1.1\r\n\r\n" ), "f", "a=>$1" ), 
+            "select coalesce((select row_to_json(t) from (select * from f (a=>$1)) t limit 1), 'null')::text;" );
+        assert_eq!( get_function_call_sql( api.set_test_request( "GET /alle HTTP/1.1\r\n\r\n" ), "f", "a=>$1" ), "select json_agg(t)::text from (select * from f (a=>$1)) t;" );
+    }
+}
+
 #[cfg(test)]
 mod test_get_sql_insert{
     use super::*;
@@ -0,0 +1,270 @@
+use deadpool_postgres::{Pool };
+use log::info;
+use serde_json::{json, Map, Value};
+
+/// Column of a table or view, or argument of a function,
+/// as found in the database catalog
+#[derive(Debug, Clone, PartialEq)]
+struct Column{
+    name: String,
+    data_type: String,
+    required: bool      // not null, without default and neither identity nor generated (or function argument without default)
+}
+
+///
+/// Skeleton of an OpenAPI file for the views, tables and functions
+/// in `schemas`, in the dialect that API understands:
+///
+/// * views: `get` with optional query parameters for all columns,
+///
+/// * tables: `get`, plus `post` (insert), `patch` (update) and `delete`
+///   with payloads described in `components/schemas`,
+///
+/// * functions: `post` with `x-query-syntax-of-method: GET` and the 
+///   arguments as payload.
+///
+/// Routes are named after the view, table or function; authentication 
+/// (`x-auth-method` etc.) and filters need to be added by hand.
+pub async fn generate_api( pool: &Pool, schemas: &[String] ) -> Result<Value, String>{
+    let client = match pool.get().await{
+        Ok (cl) => cl,
+        Err( e ) => {return Err(format!("No db client available: {:?}", e)); }
+    };
+
+    let mut paths = Map::new();
+    let mut components = Map::new();
+
+    // ---------------------------------------- 
+    // Tables and views
+    let rows = client.query( "select c.table_schema::text, c.table_name::text, t.table_type::text, c.column_name::text, 
+            c.data_type::text, c.is_nullable::text = 'NO' and c.column_default is null
+                and c.is_identity::text = 'NO' and c.is_generated::text = 'NEVER'
+        from information_schema.columns c 
+            join information_schema.tables t on t.table_schema = c.table_schema and t.table_name = c.table_name
+        where c.table_schema = any($1)
+        order by c.table_schema, c.table_name, c.ordinal_position", &[&schemas] ).await
+        .map_err( |e| format!("Cannot read tables and views: {}", e) )?;
+
+    let mut relations: Vec<(String, String, bool, Vec<Column>)> = vec![];
+    for row in rows{
+        let ( s_schema, s_name, s_type ): (String, String, String) = ( row.get(0), row.get(1), row.get(2) );
+        let column = Column{ name: row.get(3), data_type: row.get(4), required: row.get(5) };
+        match relations.last_mut(){
+            Some( r ) if r.0 == s_schema && r.1 == s_name => r.3.push( column ),
+            _ => relations.push( ( s_schema, s_name, s_type == "BASE TABLE", vec![column] ) )
+        }
+    }
+
+    for ( s_schema, s_name, b_is_table, columns ) in relations{
+        info!("Adding {} {}.{}", if b_is_table {"table"} else {"view"}, s_schema, s_name);
+        let s_path = get_path_name( &paths, &s_schema, &s_name );
+        paths.insert( s_path.to_string(), get_relation_path( &s_schema, &s_name, &s_path, b_is_table, &columns ) );
+        if b_is_table {
+            components.insert( s_path.to_string(), get_schema_component( &columns, true ) );
+            components.insert( format!("{}_patch", s_path), get_schema_component( &columns, false ) );
+        }
+    }
+
+    // ---------------------------------------- 
+    // Functions (input arguments only: modes in, inout, variadic; 
+    // without names, proargnames is null or has empty names)
+    let rows = client.query( "select n.nspname::text, p.proname::text, p.pronargdefaults::int4,
+            array( select a.name from unnest( p.proargnames, coalesce( p.proargmodes::text[], 
+                array_fill( 'i'::text, array[coalesce( array_length( p.proargnames, 1 ), 0 )] ) ) ) as a(name, mode) 
+                where a.mode in ('i', 'b', 'v') and a.name <> '' ),
+            array( select format_type( t, null ) from unnest( p.proargtypes ) t )
+        from pg_proc p join pg_namespace n on n.oid = p.pronamespace
+        where n.nspname = any($1) and p.prokind = 'f'
+        order by n.nspname, p.proname", &[&schemas] ).await
+        .map_err( |e| format!("Cannot read functions: {}", e) )?;
+
+    for row in rows{
+        let ( s_schema, s_name, n_defaults ): (String, String, i32) = ( row.get(0), row.get(1), row.get(2) );
+        let args = match get_function_args( row.get(3), row.get(4), n_defaults ){
+            Some( args ) => args,
+            None => {
+                info!("Skipping function {}.{}: not all arguments have names", s_schema, s_name);
+                continue;
+            }
+        };
+        info!("Adding function {}.{}", s_schema, s_name);
+        let s_path = get_path_name( &paths, &s_schema, &s_name );
+        paths.insert( s_path.to_string(), get_function_path( &s_schema, &s_name, &s_path ) );
+        components.insert( format!("{}_args", s_path), get_schema_component( &args, true ) );
+    }
+
+    Ok( json!({
+        "openapi": "3.0.1",
+        "info": { "title": format!("pg_api_muscle: {}", schemas.join(", ")), "description": "generated from the database catalog", "version": "0.0.1" },
+        "paths": paths,
+        "components": { "schemas": components }
+    }))
+}
+
+/// Arguments of a function from the names of its input arguments
+/// and their types (the last `n_defaults` have defaults); None if 
+/// some arguments have no name, since calls use named notation.
+fn get_function_args( names: Vec<String>, types: Vec<String>, n_defaults: i32 ) -> Option<Vec<Column>>{
+    if names.len() != types.len() { return None; }
+    let n_required = types.len().saturating_sub( n_defaults as usize );
+    Some( names.into_iter().zip( types ).enumerate()
+        .map( |(i, (name, data_type))| Column{ name, data_type, required: i < n_required } ).collect() )
+}
+
+/// Route name: the name of the view (etc.), unless this 
+/// name is taken by an object in another schema
+fn get_path_name( paths: &Map<String, Value>, s_schema: &str, s_name: &str ) -> String{
+    match paths.contains_key( s_name ){
+        true => format!("{}_{}", s_schema, s_name),
+        false => s_name.to_string()
+    }
+}
+
+fn get_relation_path( s_schema: &str, s_name: &str, s_path: &str, b_is_table: bool, columns: &[Column] ) -> Value{
+    let s_operation = format!("{}.{}", s_schema, s_name);
+    let parameters: Vec<Value> = columns.iter().map( get_query_parameter ).collect();
+    let mut path = json!({
+        "get": { "operationId": s_operation, "parameters": parameters }
+    });
+    if b_is_table {
+        path[ "post" ] = json!({ "operationId": s_operation, "parameters": [], "requestBody": get_request_body( s_path ) });
+        path[ "patch" ] = json!({ "operationId": s_operation, "parameters": parameters, 
+            "requestBody": get_request_body( &format!("{}_patch", s_path) ) });
+        path[ "delete" ] = json!({ "operationId": s_operation, "parameters": parameters });
+    }
+    path
+}
+
+fn get_function_path( s_schema: &str, s_name: &str, s_path: &str ) -> Value{
+    json!({
+        "post": {
+            "operationId": format!("{}.{}", s_schema, s_name),
+            "parameters": [],
+            "x-query-syntax-of-method": "GET",
+            "requestBody": get_request_body( &format!("{}_args", s_path) )
+        }
+    })
+}
+
+fn get_request_body( s_component: &str ) -> Value{
+    json!({
+        "content": { "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", s_component) } } },
+        "required": true
+    })
+}
+
+/// Query parameters are optional filters
+fn get_query_parameter( column: &Column ) -> Value{
+    json!({
+        "name": column.name,
+        "description": column.data_type,
+        "required": false,
+        "in": "query",
+        "schema": get_type_schema( &column.data_type )
+    })
+}
+
+/// `type` and (if there is one) `format` of a column
+fn get_type_schema( s_pg_type: &str ) -> Value{
+    let mut schema = json!({ "type": get_openapi_type( s_pg_type ) });
+    if let Some( s_format ) = get_openapi_format( s_pg_type ) { schema[ "format" ] = json!( s_format ); }
+    schema
+}
+
+/// Object schema for a payload; API expects a `required` list
+/// (even if it is empty, as for updates with `b_use_required` false).
+fn get_schema_component( columns: &[Column], b_use_required: bool ) -> Value{
+    let mut properties = Map::new();
+    for c in columns{
+        let mut property = get_type_schema( &c.data_type );
+        property[ "description" ] = json!( c.data_type );
+        properties.insert( c.name.to_string(), property );
+    }
+    let required: Vec<&str> = columns.iter().filter( |c| b_use_required && c.required ).map( |c| &c.name[..] ).collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// Postgres type -> parameter type as understood by ParameterType
+fn get_openapi_type( s_pg_type: &str ) -> &'static str{
+    match s_pg_type{
+        "smallint" | "integer" => "integer",
+        "bigint" => "bigint",
+        "numeric" | "real" | "double precision" => "number",
+        "boolean" => "boolean",
+        _ => "string"
+    }
+}
+
+/// Postgres type -> OpenAPI format, if there is one
+fn get_openapi_format( s_pg_type: &str ) -> Option<&'static str>{
+    match s_pg_type{
+        "smallint" | "integer" => Some( "int32" ),
+        "bigint" => Some( "int64" ),
+        "real" => Some( "float" ),
+        "double precision" => Some( "double" ),
+        "date" => Some( "date" ),
+        "timestamp with time zone" | "timestamp without time zone" => Some( "date-time" ),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod test_introspect{
+    use super::*;
+
+    fn columns() -> Vec<Column>{
+        vec![ Column{ name: "kapitel_id".to_string(), data_type: "integer".to_string(), required: false },
+              Column{ name: "titel".to_string(), data_type: "text".to_string(), required: true } ]
+    }
+
+    #[test]
+    fn types() {
+        assert_eq!( get_openapi_type( "integer" ), "integer" );
+        assert_eq!( get_openapi_type( "bigint" ), "bigint" );
+        assert_eq!( get_openapi_type( "double precision" ), "number" );
+        assert_eq!( get_openapi_type( "timestamp with time zone" ), "string" );
+        assert_eq!( get_type_schema( "bigint" ), json!({ "type": "bigint", "format": "int64" }) );
+        assert_eq!( get_type_schema( "timestamp with time zone" ), json!({ "type": "string", "format": "date-time" }) );
+        assert_eq!( get_type_schema( "text" ), json!({ "type": "string" }) );
+    }
+
+    #[test]
+    fn schema_component() {
+        assert_eq!( get_schema_component( &columns(), true )[ "required" ], json!(["titel"]) );
+        assert_eq!( get_schema_component( &columns(), false )[ "required" ], json!([]) );
+        assert_eq!( get_schema_component( &columns(), true )[ "properties" ][ "kapitel_id" ][ "type" ], "integer" );
+    }
+
+    #[test]
+    fn relation_path() {
+        let view = get_relation_path( "web_api", "kapitel", "kapitel", false, &columns() );
+        assert_eq!( view[ "get" ][ "operationId" ], "web_api.kapitel" );
+        assert_eq!( view[ "get" ][ "parameters" ][ 1 ][ "schema" ][ "type" ], "string" );
+        assert!( view[ "post" ].is_null() );
+
+        let table = get_relation_path( "web_api", "kapitel", "kapitel", true, &columns() );
+        assert_eq!( table[ "patch" ][ "requestBody" ][ "content" ][ "application/json" ][ "schema" ][ "$ref" ], "#/components/schemas/kapitel_patch" );
+        assert!( table[ "delete" ].is_object() );
+    }
+
+    #[test]
+    fn function_args() {
+        let s = | v: &[&str] | v.iter().map( |x| x.to_string() ).collect::<Vec<String>>();
+        // no arguments
+        assert_eq!( get_function_args( vec![], vec![], 0 ), Some( vec![] ) );
+        // unnamed arguments (f(integer, text), or f(a integer, text))
+        assert_eq!( get_function_args( vec![], s( &["integer", "text"] ), 0 ), None );
+        assert_eq!( get_function_args( s( &["a"] ), s( &["integer", "text"] ), 0 ), None );
+        let args = get_function_args( s( &["buch_id", "lim"] ), s( &["integer", "integer"] ), 1 ).unwrap();
+        assert!( args[0].required );
+        assert!( !args[1].required );
+    }
+
+    #[test]
+    fn path_name() {
+        let mut paths = Map::new();
+        assert_eq!( get_path_name( &paths, "web_api", "kapitel" ), "kapitel" );
+        paths.insert( "kapitel".to_string(), Value::Null );
+        assert_eq!( get_path_name( &paths, "archiv", "kapitel" ), "archiv_kapitel" );
+    }
+}
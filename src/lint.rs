@@ -0,0 +1,438 @@
+use crate::APIParam;
+use crate::api::ClaimItem;
+use crate::DbPools;
+use deadpool_postgres::Client;
+use std::fmt;
+use serde_json::Value;
+
+/// Problem in the OpenAPI file, located by a JSON pointer
+/// (<https://tools.ietf.org/html/rfc6901>), e.g.
+///
+/// `error at /paths/kapitel/get/operationId: missing operationId`
+///
+/// Warnings (e.g. unknown extensions) do not keep the 
+/// service from working.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiProblem{
+    pub pointer: String,
+    pub message: String,
+    pub is_error: bool
+}
+
+impl ApiProblem{
+    fn error( pointer: &str, message: &str ) -> Self{
+        ApiProblem{ pointer: pointer.to_string(), message: message.to_string(), is_error: true }
+    }
+    fn warning( pointer: &str, message: &str ) -> Self{
+        ApiProblem{ pointer: pointer.to_string(), message: message.to_string(), is_error: false }
+    }
+}
+
+impl fmt::Display for ApiProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}: {}", if self.is_error {"error"} else {"warning"}, 
+            if self.pointer.is_empty() {"/"} else {&self.pointer}, self.message)
+    }
+}
+
+/// Extensions of OpenAPI that pg_api_muscle understands
+const KNOWN_EXTENSIONS: &[&str] = &[
+    "x-query-syntax-of-method",
+    "x-auth-method",
+    "x-claim-custom",
+    "x-set-auth-cookie",
+    "x-scope-claim",
+    "x-database",
+    "x-read-only",
+    "x-statement-timeout",
+    "x-allow-unfiltered",
+    "x-max-affected-rows",
+    "x-prefer-return",
+    "x-primary-key",
+    "x-version-column",
+    "x-function",
+    "x-body-as-single-argument",
+    "x-returns",
+    "x-content-type",
+];
+
+const AUTH_METHODS: &[&str] = &[ "forward_jwt_bearer", "api_key", "cookie_jwt" ];
+
+const METHODS: &[&str] = &[ "get", "post", "patch", "delete" ];
+
+// Fields of a path item that are no operations
+const PATH_ITEM_FIELDS: &[&str] = &[ "summary", "description", "parameters", "servers", "$ref" ];
+
+///
+/// Checks the OpenAPI document for problems that would otherwise
+/// only show up per request (as "no route", ignored parameters, or
+/// a panic):
+///
+/// * `$ref`s that point nowhere,
+/// * request bodies whose schema lacks properties, types, or the `required` list,
+/// * query parameters that do not have the expected fields,
+/// * methods other than GET, POST, PATCH and DELETE,
+/// * missing or malformed `operationId`s,
+/// * unknown `x-` extensions (warning) and invalid values of known ones.
+pub fn check_api( api: &Value ) -> Vec<ApiProblem>{
+    let mut problems = vec![];
+    check_refs( api, api, "", &mut problems );
+    check_extensions( api, "", &mut problems );
+
+    let paths = match api[ "paths" ].as_object(){
+        Some( p ) => p,
+        None => { problems.push( ApiProblem::error( "/paths", "no paths defined" ) ); return problems; }
+    };
+
+    for ( s_path, path_item ) in paths{
+        let s_path_pointer = format!("/paths/{}", escape( s_path ));
+        let ops = match path_item.as_object(){
+            Some( o ) => o,
+            None => { problems.push( ApiProblem::error( &s_path_pointer, "path item is not an object" ) ); continue; }
+        };
+        for ( s_method, op ) in ops{
+            if PATH_ITEM_FIELDS.contains( &&s_method[..] ) || s_method.starts_with( "x-" ) { continue; }
+            let s_pointer = format!("{}/{}", s_path_pointer, escape( s_method ));
+            if !METHODS.contains( &&s_method[..] ) {
+                problems.push( ApiProblem::error( &s_pointer, &format!("unsupported method `{}`; use get, post, patch or delete", s_method) ) );
+                continue;
+            }
+            check_operation( api, op, s_method, &s_pointer, &mut problems );
+        }
+    }
+    problems
+}
+
+fn check_operation( api: &Value, op: &Value, s_method: &str, s_pointer: &str, problems: &mut Vec<ApiProblem> ){
+    match op[ "operationId" ].as_str(){
+        Some( id ) if is_valid_operation_id( id ) => {},
+        Some( id ) => problems.push( ApiProblem::error( &format!("{}/operationId", s_pointer), 
+            &format!("`{}` is no valid name of a view, table or function (schema.name)", id) ) ),
+        None => problems.push( ApiProblem::error( &format!("{}/operationId", s_pointer), "missing operationId" ) )
+    }
+
+    if !op[ "parameters" ].is_null() {
+        if let Err( e ) = serde_json::from_value::<Vec<APIParam>>( op[ "parameters" ].clone() ){
+            problems.push( ApiProblem::error( &format!("{}/parameters", s_pointer), 
+                &format!("parameters are ignored, they need name, description, in, required, and schema with type and format: {}", e) ) );
+        }
+    }
+
+    if s_method == "post" || s_method == "patch" {
+        check_request_body( api, op, s_pointer, problems );
+    }
+
+    if let Some( m ) = op[ "x-auth-method" ].as_str(){
+        if !AUTH_METHODS.contains( &m ) {
+            problems.push( ApiProblem::error( &format!("{}/x-auth-method", s_pointer), &format!("unknown authentication method `{}`", m) ) );
+        }
+    }
+
+    if !op[ "x-query-syntax-of-method" ].is_null() && op[ "x-query-syntax-of-method" ] != "GET" {
+        problems.push( ApiProblem::error( &format!("{}/x-query-syntax-of-method", s_pointer), "only \"GET\" is supported" ) );
+    }
+
+    if !op[ "x-allow-unfiltered" ].is_null() && !op[ "x-allow-unfiltered" ].is_boolean() {
+        problems.push( ApiProblem::error( &format!("{}/x-allow-unfiltered", s_pointer), "must be true or false" ) );
+    }
+
+    if !op[ "x-max-affected-rows" ].is_null() && !op[ "x-max-affected-rows" ].is_u64() {
+        problems.push( ApiProblem::error( &format!("{}/x-max-affected-rows", s_pointer), "must be a number of rows" ) );
+    }
+
+    if let Some( p ) = op[ "x-prefer-return" ].as_str(){
+        if crate::ReturnPreference::from( p ).is_none() {
+            problems.push( ApiProblem::error( &format!("{}/x-prefer-return", s_pointer), "must be minimal, representation or headers-only" ) );
+        }
+    }
+
+    if !op[ "x-version-column" ].is_null() && !op[ "x-version-column" ].is_string() {
+        problems.push( ApiProblem::error( &format!("{}/x-version-column", s_pointer), "must be a column name" ) );
+    }
+
+    if !op[ "x-function" ].is_null() && !op[ "x-function" ].is_boolean() {
+        problems.push( ApiProblem::error( &format!("{}/x-function", s_pointer), "must be true or false" ) );
+    }else if op[ "x-function" ] == true && s_method != "get" {
+        problems.push( ApiProblem::warning( &format!("{}/x-function", s_pointer), 
+            "is ignored except on GET (POST and PATCH call functions with x-query-syntax-of-method)" ) );
+    }
+
+    if !op[ "x-body-as-single-argument" ].is_null() && !op[ "x-body-as-single-argument" ].is_string() {
+        problems.push( ApiProblem::error( &format!("{}/x-body-as-single-argument", s_pointer), "must be the name of a function argument" ) );
+    }else if !op[ "x-body-as-single-argument" ].is_null() && op[ "x-query-syntax-of-method" ] != "GET" {
+        problems.push( ApiProblem::warning( &format!("{}/x-body-as-single-argument", s_pointer), 
+            "is ignored unless the operation calls a function (x-query-syntax-of-method: GET)" ) );
+    }
+
+    let b_calls_function = op[ "x-query-syntax-of-method" ] == "GET" || ( s_method == "get" && op[ "x-function" ] == true );
+    let b_returns_valid = match &op[ "x-returns" ]{
+        Value::Null => true,
+        r => r.as_str().and_then( crate::FunctionResult::from ).is_some()
+    };
+    let b_content_type_valid = op[ "x-content-type" ].is_null() || op[ "x-content-type" ].is_string();
+    if !b_returns_valid {
+        problems.push( ApiProblem::error( &format!("{}/x-returns", s_pointer), "must be scalar, single-object, array or raw" ) );
+    }
+    if !b_content_type_valid {
+        problems.push( ApiProblem::error( &format!("{}/x-content-type", s_pointer), "must be a media type, e.g. text/html" ) );
+    }
+    for ( s_ext, b_valid ) in [( "x-returns", b_returns_valid ), ( "x-content-type", b_content_type_valid )]{
+        if b_valid && !op[ s_ext ].is_null() && !b_calls_function {
+            problems.push( ApiProblem::warning( &format!("{}/{}", s_pointer, s_ext), 
+                "is ignored unless the operation calls a function (x-function, x-query-syntax-of-method)" ) );
+        }
+    }
+
+    let primary_key = &op[ "x-primary-key" ];
+    if !primary_key.is_null() && !primary_key.is_string() 
+        && !primary_key.as_array().is_some_and( |a| !a.is_empty() && a.iter().all( |k| k.is_string() ) ) {
+        problems.push( ApiProblem::error( &format!("{}/x-primary-key", s_pointer), "must be a column name or a list of column names" ) );
+    }else if !primary_key.is_null() && s_method == "post" {
+        // the Location of a new row is a GET route of the same table
+        let b_has_get = api[ "paths" ].as_object().is_some_and( 
+            |paths| paths.values().any( |item| item[ "get" ][ "operationId" ] == op[ "operationId" ] ) );
+        if !b_has_get {
+            problems.push( ApiProblem::warning( &format!("{}/x-primary-key", s_pointer), 
+                "no GET route has this operationId, so inserts get no Location" ) );
+        }
+    }
+
+    if !op[ "x-claim-custom" ].is_null() {
+        if let Err( e ) = serde_json::from_value::<Vec<ClaimItem>>( op[ "x-claim-custom" ].clone() ){
+            problems.push( ApiProblem::error( &format!("{}/x-claim-custom", s_pointer), &format!("invalid claim check: {}", e) ) );
+        }
+    }
+}
+
+/// POST and PATCH need a request body with a schema of flat, typed
+/// properties and a `required` list (which may be empty)
+fn check_request_body( api: &Value, op: &Value, s_pointer: &str, problems: &mut Vec<ApiProblem> ){
+    let s_schema_pointer = format!("{}/requestBody/content/application~1json/schema", s_pointer);
+    let schema = &op[ "requestBody" ][ "content" ][ "application/json" ][ "schema" ];
+    let ( schema, s_schema_pointer ) = match schema[ "$ref" ].as_str(){
+        Some( r ) if r.starts_with( '#' ) => match api.pointer( &r[1..] ){
+            Some( s ) => ( s, r[1..].to_string() ),
+            None => return      // reported as dangling $ref
+        },
+        _ => ( schema, s_schema_pointer )
+    };
+    if schema.is_null() {
+        problems.push( ApiProblem::error( &s_schema_pointer, "missing request body schema (no route)" ) );
+        return;
+    }
+    match schema[ "properties" ].as_object(){
+        Some( props ) => for ( s_name, prop ) in props{
+            if prop[ "type" ].as_str().is_none() {
+                problems.push( ApiProblem::error( &format!("{}/properties/{}", s_schema_pointer, escape( s_name )), "property without type" ) );
+            }
+        },
+        None => problems.push( ApiProblem::error( &s_schema_pointer, "schema without properties (no route)" ) )
+    }
+    if !schema[ "required" ].is_array() {
+        problems.push( ApiProblem::error( &format!("{}/required", s_schema_pointer), "missing list of required properties (no route)" ) );
+    }
+}
+
+fn check_refs( api: &Value, node: &Value, s_pointer: &str, problems: &mut Vec<ApiProblem> ){
+    match node{
+        Value::Object( o ) => for ( key, val ) in o{
+            let s_child = format!("{}/{}", s_pointer, escape( key ));
+            if key == "$ref" {
+                match val.as_str(){
+                    Some( r ) if r.starts_with( '#' ) => if api.pointer( &r[1..] ).is_none() {
+                        problems.push( ApiProblem::error( &s_child, &format!("dangling $ref `{}`", r) ) );
+                    },
+                    Some( r ) => problems.push( ApiProblem::error( &s_child, &format!("$ref `{}` is not local (#/...)", r) ) ),
+                    None => problems.push( ApiProblem::error( &s_child, "$ref is not a string" ) )
+                }
+            }else{
+                check_refs( api, val, &s_child, problems );
+            }
+        },
+        Value::Array( a ) => for ( i, val ) in a.iter().enumerate(){
+            check_refs( api, val, &format!("{}/{}", s_pointer, i), problems );
+        },
+        _ => {}
+    }
+}
+
+fn check_extensions( node: &Value, s_pointer: &str, problems: &mut Vec<ApiProblem> ){
+    match node{
+        Value::Object( o ) => for ( key, val ) in o{
+            let s_child = format!("{}/{}", s_pointer, escape( key ));
+            if key.starts_with( "x-" ) && !KNOWN_EXTENSIONS.contains( &&key[..] ) {
+                problems.push( ApiProblem::warning( &s_child, &format!("unknown extension `{}` is ignored", key) ) );
+            }
+            // examples and schemas may contain anything
+            if key != "example" && key != "examples" { check_extensions( val, &s_child, problems ); }
+        },
+        Value::Array( a ) => for ( i, val ) in a.iter().enumerate(){
+            check_extensions( val, &format!("{}/{}", s_pointer, i), problems );
+        },
+        _ => {}
+    }
+}
+
+/// operationId is the (optionally schema qualified) name of a view, 
+/// table or function, which pg_api_muscle puts into SQL unquoted:
+/// [schema.]name, with letters, digits, _ and $ (not leading).
+fn is_valid_operation_id( s_id: &str ) -> bool{
+    let parts: Vec<&str> = s_id.split( '.' ).collect();
+    parts.len() <= 2 && parts.iter().all( |p| {
+        let mut chars = p.chars();
+        match chars.next(){
+            Some( c ) if c.is_alphabetic() || c == '_' => chars.all( |c| c.is_alphanumeric() || c == '_' || c == '$' ),
+            _ => false
+        }
+    })
+}
+
+/// JSON pointer escaping of a key: ~ -> ~0, / -> ~1
+fn escape( s_key: &str ) -> String{
+    s_key.replace( '~', "~0" ).replace( '/', "~1" )
+}
+
+/// Checks that `x-database` (at the top of the file or on 
+/// operations) names a configured database.
+pub fn check_databases( api: &Value, database_names: &[String] ) -> Vec<ApiProblem>{
+    let mut problems = vec![];
+    let mut check = | val: &Value, s_pointer: &str | {
+        match val{
+            Value::Null => {},
+            Value::String( s ) if database_names.contains( s ) => {},
+            _ => problems.push( ApiProblem::error( s_pointer, &format!("no database named {} (configured: {})", val, database_names.join( ", " )) ) )
+        }
+    };
+    check( &api[ "x-database" ], "/x-database" );
+    if let Some( paths ) = api[ "paths" ].as_object(){
+        for ( s_path, path_item ) in paths{
+            for method in METHODS{
+                check( &path_item[ method ][ "x-database" ], &format!("/paths/{}/{}/x-database", escape( s_path ), method) );
+            }
+        }
+    }
+    problems
+}
+
+/// Checks that the operationIds exist in the database 
+/// of their route (`x-database`, or `s_default_db`): 
+/// routes with `x-query-syntax-of-method: GET` (and GET routes with
+/// `x-function: true`) need a function, all others a table or view.
+pub async fn check_api_against_db( pools: &DbPools, s_default_db: &str, api: &Value ) -> Vec<ApiProblem>{
+    let mut clients: Vec<(String, Client)> = vec![];
+    let mut problems = vec![];
+    let paths = match api[ "paths" ].as_object(){
+        Some( p ) => p,
+        None => return problems
+    };
+    for ( s_path, path_item ) in paths{
+        for method in METHODS{
+            let op = &path_item[ method ];
+            let s_id = match op[ "operationId" ].as_str(){
+                Some( id ) if is_valid_operation_id( id ) => id,
+                _ => continue
+            };
+            let s_pointer = format!("/paths/{}/{}/operationId", escape( s_path ), method);
+            let s_db = op[ "x-database" ].as_str().or_else( || api[ "x-database" ].as_str() ).unwrap_or( s_default_db );
+
+            if !clients.iter().any( |( name, _ )| name == s_db ) {
+                let pool = match pools.get( s_db ){
+                    Some( p ) => p,
+                    None => continue        // reported by check_databases
+                };
+                match pool.get().await{
+                    Ok (cl) => clients.push( ( s_db.to_string(), cl ) ),
+                    Err( e ) => { 
+                        problems.push( ApiProblem::error( &s_pointer, &format!("cannot check operationId, no db client available for `{}`: {:?}", s_db, e) ) );
+                        continue;
+                    }
+                }
+            }
+            let client = &clients.iter().find( |( name, _ )| name == s_db ).unwrap().1;
+
+            let b_is_function = op[ "x-query-syntax-of-method" ] == "GET" || ( *method == "get" && op[ "x-function" ] == true );
+            let sql = match b_is_function{
+                true => "select exists( select 1 from pg_proc p join pg_namespace n on n.oid = p.pronamespace 
+                    where ( n.nspname || '.' || p.proname = $1 ) or ( p.proname = $1 and n.nspname = any( current_schemas( false ) ) ) )",
+                false => "select to_regclass( $1 ) is not null"
+            };
+            match client.query_one( sql, &[&s_id] ).await{
+                Ok( row ) => if !row.get::<_, bool>(0) {
+                    problems.push( ApiProblem::error( &s_pointer, &format!("{} `{}` does not exist in database `{}`", 
+                        if b_is_function {"function"} else {"table or view"}, s_id, s_db) ) );
+                },
+                Err( e ) => problems.push( ApiProblem::error( &s_pointer, &format!("cannot check `{}`: {}", s_id, e) ) )
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod test_check_api{
+    use super::*;
+
+    fn json( s: &str ) -> Value{
+        serde_json::from_str( s ).unwrap()
+    }
+
+    fn pointers( problems: &[ApiProblem] ) -> Vec<String>{
+        problems.iter().map( |p| p.pointer.to_string() ).collect()
+    }
+
+    #[test]
+    fn valid() {
+        let api = json( r##"{"paths": {"kapitel": {
+            "get": {"operationId": "web_api.kapitel", "parameters": [{"name": "kapitel_id", "description": "", "in": "query", 
+                "required": true, "schema": {"type": "integer", "format": "integer"}}]},
+            "patch": {"operationId": "web_api.kapitel", "x-auth-method": "forward_jwt_bearer",
+                "requestBody": {"content": {"application/json": {"schema": {"$ref": "#/components/schemas/kapitel"}}}}}}},
+            "components": {"schemas": {"kapitel": {"properties": {"titel": {"type": "string"}}, "required": []}}}}"## );
+        assert_eq!( check_api( &api ), vec![] );
+    }
+
+    #[test]
+    fn dangling_ref() {
+        let api = json( r##"{"paths": {"a/b": {"post": {"operationId": "f", 
+            "requestBody": {"content": {"application/json": {"schema": {"$ref": "#/components/schemas/nix"}}}}}}}}"## );
+        assert_eq!( pointers( &check_api( &api ) ), vec!["/paths/a~1b/post/requestBody/content/application~1json/schema/$ref"] );
+    }
+
+    #[test]
+    fn schema_problems() {
+        let api = json( r##"{"paths": {"k": {"post": {"operationId": "f", 
+            "requestBody": {"content": {"application/json": {"schema": {"properties": {"a": {"format": "x"}}}}}}}}}}"## );
+        assert_eq!( pointers( &check_api( &api ) ), vec![
+            "/paths/k/post/requestBody/content/application~1json/schema/properties/a",
+            "/paths/k/post/requestBody/content/application~1json/schema/required"] );
+    }
+
+    #[test]
+    fn operations() {
+        let api = json( r##"{"paths": {"k": {"put": {"operationId": "f"}, "get": {"operationId": "a.b.c", "x-foo": 1,
+            "parameters": [{"name": "a"}]}, "delete": {"x-auth-method": "basic", "x-allow-unfiltered": "yes", "x-max-affected-rows": -1,
+            "x-prefer-return": "full", "x-primary-key": [], "x-version-column": 1, "x-function": "yes", "x-body-as-single-argument": 1, "x-returns": "json"}, "post": {"operationId": "g", "x-primary-key": "id", "x-function": true, "x-body-as-single-argument": "b", "x-content-type": "text/csv",
+            "requestBody": {"content": {"application/json": {"schema": {"type": "object", "properties": {}, "required": []}}}}}}}}"## );
+        let problems = check_api( &api );
+        assert_eq!( problems.iter().filter( |p| p.is_error ).count(), 13 );
+        assert_eq!( problems.iter().filter( |p| !p.is_error ).map( |p| &p.pointer[..] ).collect::<Vec<&str>>(), 
+            vec!["/paths/k/get/x-foo", "/paths/k/post/x-function", "/paths/k/post/x-body-as-single-argument", "/paths/k/post/x-content-type", "/paths/k/post/x-primary-key"] );
+    }
+
+    #[test]
+    fn databases() {
+        let api = json( r##"{"x-database": "default", "paths": {"k": {"get": {"operationId": "f", "x-database": "anglistik"}, 
+            "post": {"x-database": "nix"}}}}"## );
+        let names = vec!["default".to_string(), "anglistik".to_string()];
+        assert_eq!( pointers( &check_databases( &api, &names ) ), vec!["/paths/k/post/x-database"] );
+    }
+
+    #[test]
+    fn operation_ids() {
+        assert!( is_valid_operation_id( "web_api.kapitel" ) );
+        assert!( is_valid_operation_id( "kapitel_2" ) );
+        assert!( !is_valid_operation_id( "web_api.kapitel; drop table x" ) );
+        assert!( !is_valid_operation_id( "2kapitel" ) );
+        assert!( !is_valid_operation_id( "" ) );
+    }
+}
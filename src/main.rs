@@ -1,23 +1,31 @@
-use std::{borrow::BorrowMut, convert::TryInto, env, error::Error, fmt::{self, Formatter, Display}, fs::File, io::prelude::*, net::Ipv4Addr, ops::AddAssign, process::exit, sync::Arc};
+use std::{borrow::BorrowMut, convert::TryInto, env, error::Error, collections::HashMap, fmt::{self, Formatter, Display}, fs::File, future::Future, io::prelude::*, net::Ipv4Addr, ops::AddAssign, process::exit, sync::Arc};
 use futures::lock::Mutex;
 use tini::Ini;
-use native_tls::Identity;
+use native_tls::{Certificate, Identity};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio_native_tls::TlsStream;
 use self::request::Request;
 use self::response::Response;
 use self::api::API;
 use log::info;
 use log::error;
+use log::warn;
 use std::time::Duration;
-use deadpool_postgres::{Config, ManagerConfig, Pool, RecyclingMethod };
+use deadpool_postgres::{Config, Manager, ManagerConfig, Pool, RecyclingMethod };
+use tokio_postgres::config::SslMode;
+use deadpool::managed::{PoolConfig, Timeouts};
+use postgres_native_tls::MakeTlsConnector;
 use tokio_postgres::NoTls;
 
 mod db;
 mod request;
 mod response;
 mod api;
+mod api_file;
+mod introspect;
+mod lint;
 
 #[macro_use]
 extern crate serde;
@@ -45,6 +53,8 @@ pub enum ParameterType{
     BIGINT,
     BOOLEAN,
     NUMBER,
+    OBJECT,
+    ARRAY,
     UNKNOWN
 }
     
@@ -56,6 +66,8 @@ impl ParameterType{
             "boolean" => ParameterType::BOOLEAN,
             "bigint" => ParameterType::BIGINT,
             "number" => ParameterType::NUMBER,
+            "object" => ParameterType::OBJECT,
+            "array" => ParameterType::ARRAY,
             _ => ParameterType::UNKNOWN,
         }
     }
@@ -90,6 +102,71 @@ impl Default for Authentication {
     fn default() -> Self { Authentication::UNKNOWN }
 }
 
+/// How a request that needs authentication is 
+/// authenticated (OpenAPI: x-auth-method)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMethod{
+    JWTBEARER,      // forward_jwt_bearer
+    APIKEY,         // api_key
+    COOKIE,         // cookie_jwt
+    NONE
+}
+
+impl Default for AuthMethod {
+    fn default() -> Self { AuthMethod::NONE }
+}
+
+/// What a POST, PATCH or DELETE responds with (header 
+/// `Prefer: return=...`, or OpenAPI: x-prefer-return)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReturnPreference{
+    MINIMAL,        // minimal: 204, no content
+    REPRESENTATION, // representation: the affected rows as JSON array
+    HEADERSONLY,    // headers-only: 201 with the Location of the created row (POST)
+    DEFAULT         // the row (POST, PATCH) or the number of rows (DELETE)
+}
+
+impl ReturnPreference{
+    pub fn from( s_name: &str ) -> Option<Self>{
+        match s_name{
+            "minimal" => Some( ReturnPreference::MINIMAL ),
+            "representation" => Some( ReturnPreference::REPRESENTATION ),
+            "headers-only" => Some( ReturnPreference::HEADERSONLY ),
+            _ => None
+        }
+    }
+
+    pub fn as_str( &self ) -> &'static str{
+        match self{
+            ReturnPreference::MINIMAL => "minimal",
+            ReturnPreference::REPRESENTATION => "representation",
+            ReturnPreference::HEADERSONLY => "headers-only",
+            ReturnPreference::DEFAULT => "default"
+        }
+    }
+}
+
+/// What a function call responds with (OpenAPI: x-returns)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionResult{
+    SCALAR,         // scalar: the value as JSON, e.g. 5 rather than [{"f": 5}]
+    SINGLEOBJECT,   // single-object: the first row as JSON object
+    ARRAY,          // array: all rows as JSON array (default)
+    RAW             // raw: the value as it is (text or bytea), see x-content-type
+}
+
+impl FunctionResult{
+    pub fn from( s_name: &str ) -> Option<Self>{
+        match s_name{
+            "scalar" => Some( FunctionResult::SCALAR ),
+            "single-object" => Some( FunctionResult::SINGLEOBJECT ),
+            "array" => Some( FunctionResult::ARRAY ),
+            "raw" => Some( FunctionResult::RAW ),
+            _ => None
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ParamVal {
     Int(i32),
@@ -128,8 +205,25 @@ impl VarStream{
             VarStream::Insecure( ds ) => ds.write_all(buffer).await
         }
     }
+    // Completes when the client is gone: EOF or a failing read.
+    // What arrives meanwhile (pipelined requests) is kept, up
+    // to MAX_PIPELINED bytes
+    async fn wait_for_disconnect(&mut self, pipelined: &mut Vec<u8>){
+        let mut probe = vec![0u8; 1024];
+        loop {
+            match self.read( &mut probe ).await {
+                Ok( 0 ) | Err( _ ) => return,
+                Ok( n ) => {
+                    let n_keep = n.min( MAX_PIPELINED.saturating_sub( pipelined.len() ) );
+                    pipelined.extend_from_slice( &probe[ ..n_keep ] );
+                }
+            }
+        }
+    }
 }
 
+const MAX_PIPELINED: usize = 16384;
+
 const S_EMPTY: String = String::new();
 
 // ----------------------------------------------------------------------------------------
@@ -140,7 +234,8 @@ const S_EMPTY: String = String::new();
 #[derive(Serialize, Deserialize, Debug)]
 struct Schema {
     r#type: String,
-    format: String
+    #[serde(default)]
+    format: String      // optional in OpenAPI
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -162,6 +257,34 @@ struct APIError {
     hint: String
 }
 
+/// Reloads the API when api_conf (or a file it refers to) changes,
+/// and on SIGHUP. The API in use is only replaced by a valid one
+/// (see `API::reload_api`).
+async fn watch_api( api: Arc<Mutex<API>>, interval_ms: u64 ){
+    let mut hangup = match signal( SignalKind::hangup() ){
+        Ok( s ) => s,
+        Err( e ) => { error!("Cannot listen for SIGHUP, API is not reloaded on signal: {}", e); return; }
+    };
+    // interval 0: wait for SIGHUP only (ticks daily, without checking)
+    let mut interval = tokio::time::interval( Duration::from_millis( if interval_ms == 0 { 86_400_000 } else { interval_ms } ));
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("SIGHUP: reloading the API");
+                let _ = api.lock().await.reload_api();
+            },
+            _ = interval.tick() => {
+                let mut api = api.lock().await;
+                if interval_ms > 0 && api.is_api_file_changed() {
+                    info!("API file changed");
+                    let _ = api.reload_api();
+                }
+            }
+        }
+    }
+}
+
 /// If .ini has `api_use_eq_syntax_on_url_parameters=true`,
 /// (enabling http.../url?param=eq.1&...)
 /// this enum lists the possible relations, eq, lt etc.
@@ -277,9 +400,10 @@ impl UnCheckedParam{
         }
     }
 
-    // @TODO: required needs to be followed up: the information is 
-    // handed over as a parameter here, NEEDS THINKING
-    // (Can there be a missing value of a non-required parameter?)
+    // A missing value of a non-required parameter (e.g. an
+    // argument with a default, or a column that a PATCH leaves 
+    // unchanged) is no problem; it is left out like optional
+    // query parameters.
     pub fn new_payload_parameter(name: &str, o_value: Option<&Value>, expected_type: ParameterType, required: bool) -> Self{
          
         match o_value{
@@ -290,7 +414,7 @@ impl UnCheckedParam{
             },
             None => {
                 if required{ UnCheckedParam::new_err_missing_parameter(name)}
-                else{panic!("Something wrong: there's an unrequired parameter without a value ... ?");}
+                else{ UnCheckedParam::new_err_non_required_parameter_missing() }
             }
 
         }
@@ -356,6 +480,15 @@ impl UnCheckedParam{
                 true => (ParamVal::Float( value.as_f64().unwrap()), S_EMPTY),
                 false => (ParamVal::Text(S_EMPTY),format!("Not a float number: `{}`", value))
             }
+            // handed to Postgres as JSON text (e.g. for json arguments)
+            ParameterType::OBJECT => match value.is_object(){
+                true => (ParamVal::Text( value.to_string()), S_EMPTY),
+                false => (ParamVal::Text(S_EMPTY),format!("Not an object: `{}`", value))
+            }
+            ParameterType::ARRAY => match value.is_array(){
+                true => (ParamVal::Text( value.to_string()), S_EMPTY),
+                false => (ParamVal::Text(S_EMPTY),format!("Not an array: `{}`", value))
+            }
             _ => (ParamVal::Text(S_EMPTY),format!("Unknown type expected, giving up."))
 
         }
@@ -398,26 +531,76 @@ pub struct UnCheckedParam{
     use_extended_syntax: bool,      // and =lt.6 represents <6 for the database. Possible relations (=, < etc.)
     value: ParamVal                 // are represented in CPRelation 
 }
+// ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+// Database (section `Database` or `[Database.<name>]`)
+#[derive (Debug, Clone)]
+pub struct DatabaseConfig{
+    name: String,                    // "default" for section `Database`
+    db: String,                      // Name of Postgres (Pg) db
+    db_user: String,                 // Name of Pg user
+    db_pass: String,                 // Password of Pg user
+    timezone: String,                // Timezone to set Pg to
+    session: Vec<(String, String)>,  // Settings of each connection (`[Database.session]`), e.g. search_path
+    replica: String,                 // Database (by name) that read-only requests go to, or "none"
+    replica_timeout_ms: u64,         // Use the primary if the replica has no connection ready within ... ms
+    host: String,                    // Host or socket folder ("none": default socket)
+    port: Option<u16>,
+    sslmode: String,                 // disable, prefer, require ("none": no TLS support)
+    ssl_ca_file: String,             // PEM file with the CA of the server certificate, or "none"
+    ssl_cert_file: String,           // PKCS12 file with a client certificate, or "none" ...
+    ssl_cert_pass: String,           // ... and its password
+    application_name: String,        // Shows up in pg_stat_activity
+    pool_max_size: Option<usize>,    // Connections in the pool (default: 4 x number of cpus)
+    pool_wait_timeout_ms: u64,       // Give up (503) if no connection is free after ... ms (default: 5000)
+    pool_create_timeout_ms: Option<u64>,
+    pool_recycle_timeout_ms: Option<u64>
+}
+
+/// One pool of db connections per database (by name)
+pub type DbPools = HashMap<String, Pool>;
+
 // ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
 // MuscleConfiguration parameters
-#[derive (Debug)]
+#[derive (Debug, Clone)]
 pub struct MuscleConfig{
     port: usize,                     // Server port
     addr: String,                    // Server address
-    db: String,                      // Name of Postgres (Pg) db
-    db_user: String,                 // Name of Pg user
-    db_pass: String,                 // Password of Pg user
+    databases: Vec<DatabaseConfig>,  // Section `Database` ("default") and `[Database.<name>]` sections
+    database: String,                // Name of the database of this API's routes (unless x-database says otherwise)
+    primary_header: String,          // Requests with this header do not go to a replica (read-after-write)
     cert_pass: String,               // Pwd for server certificate (TLS/Https)
     cert_file: String,               // Certificate file (TLS/Https)
     api_conf: String,                // OpenAPI config file containing endpoints
+    api_prefix: String,              // Path prefix of the API, e.g. "v2" ("" for the API of section `Webservice`)
+    api_watch_interval_ms: u64,      // Check api_conf for changes this often (0: only reload on SIGHUP)
     static_files_folder: String, // Path to serve static files from
     token_name: String,              // Pg token name: @TODO
     token_secret: String,            // Pg shared token secret: @TODO
     pg_setvar_prefix: String,        // Pg prefix for variables that are set in postgres through the token: @TODO
-    timezone: String,                // Timezone to set Pg to
+    pg_role_allow: Vec<String>,      // Roles that a token claim may switch to (SET LOCAL ROLE); empty: no switching
+    pg_anon_role: String,            // Role for routes without x-auth-method (or "none")
+    api_key_header: String,          // Header containing the API key (x-auth-method: api_key), or "none"
+    api_key_query_param: String,     // Query parameter containing the API key, or "none"
+    api_key_file: String,            // JSON file: API keys and their claims, or "none"
+    api_key_pg_function: String,     // Pg function: API key -> claims (json) or null, or "none"
+    auth_cookie_name: String,        // Cookie containing the JWT (x-auth-method: cookie_jwt)
+    auth_cookie_secure: bool,        // Set the cookies with `Secure` (only sent over https)?
+    csrf_cookie_name: String,        // Cookie with CSRF token that must be repeated in csrf_header, or "none"
+    csrf_header: String,             // Header repeating the CSRF token for POST, PATCH, DELETE
+    csrf_allowed_origins: Vec<String>, // Origins allowed for POST, PATCH, DELETE with cookie auth (empty: no check)
+    scope_claim: String,             // Claim containing the scopes that OpenAPI `security` requires
     static_404_default: String,      // Default Err page for "not found" -- none if set to "none"
+    retry_after_s: u64,              // Retry-After (seconds) when there is no db connection (503)
+    statement_timeout: String,       // Statement timeout of routes without x-statement-timeout (or "none")
+    allow_unfiltered: bool,          // Allow DELETE and PATCH without where on routes without x-allow-unfiltered?
     dynamic_err: String,             // Default Err JSON msg for errors in dynamic requests (or "none", meaning detailed error messages will be returned instead)
     index_file: String,              // File to return if a folder is requested (or "none")
+    api_doc_path: String,            // Path serving the OpenAPI document (or "none")
+    api_doc_server_url: String,      // `servers` url in the served document (or "none": derived from addr, port, https)
+    api_doc_strip_extensions: bool,  // Remove x- extensions from the served document?
+    api_doc_filter_by_token: bool,   // List only operations that the caller's token grants access to?
+    api_explorer_path: String,       // Path serving an HTML page to explore the document (or "none")
+    batch_path: String,              // Path accepting POSTed batches of operations (or "none")
     server_read_timeout_ms: u64,     // Tweak @TODO
     server_read_chunksize: usize,     // Tweak @TODO
     server_use_https: bool,           // Listen for https requests (true) or http?
@@ -436,6 +619,68 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
     }
 
     let pg_api_muscle_config = Arc::new(get_conf( &args[1] ));
+    let pools = get_db_pools( &pg_api_muscle_config );
+    let api_confs: Vec<Arc<MuscleConfig>> = get_api_confs( &args[1], &pg_api_muscle_config )
+        .into_iter().map( Arc::new ).collect();
+
+    // -------------------------------------------------------
+    // Command line modes instead of the service:
+    // --generate-api <schema,schema...> [outfile] [database]
+    // --check-api
+    if args.len() > 2 {
+        return match &args[2][..]{
+            "--generate-api" => {
+                let schemas: Vec<String> = args.get(3).map( |s| s.split(',').map( |x| x.trim().to_string() ).collect() )
+                    .unwrap_or_else( || vec!["public".to_string()] );
+                let s_db = args.get(5).map( |s| &s[..] ).unwrap_or( "default" );
+                let pool = pools.get( s_db ).ok_or_else( || format!("No database named `{}`", s_db) )?;
+                let api = introspect::generate_api( pool, &schemas ).await?;
+                let s_api = serde_json::to_string_pretty( &api )?;
+                match args.get(4){
+                    Some( s_file ) => { File::create( s_file )?.write_all( s_api.as_bytes() )?; info!("API written to `{}`", s_file); },
+                    None => println!("{}", s_api)
+                }
+                Ok(())
+            },
+            "--check-api" => {
+                let mut i_errors_total = 0;
+                for conf in &api_confs{
+                    let api = API::read_api_file( &conf.api_conf )?;
+                    let mut problems = lint::check_api( &api );
+                    problems.append( &mut lint::check_databases( &api, &conf.get_database_names() ) );
+                    problems.append( &mut lint::check_api_against_db( &pools, &conf.database, &api ).await );
+                    for problem in &problems { println!("{}", problem); }
+                    let i_errors = problems.iter().filter( |p| p.is_error ).count();
+                    println!("{} error(s), {} warning(s) in `{}`", i_errors, problems.len() - i_errors, conf.api_conf);
+                    i_errors_total += i_errors;
+                }
+                if i_errors_total > 0 { exit( 1 ); }
+                Ok(())
+            },
+            x => {
+                error!("Unknown command line argument `{}`", x);
+                Err( format!("Unknown command line argument `{}`; use --generate-api <schemas> [outfile] or --check-api", x).into() )
+            }
+        };
+    }
+
+    // -------------------------------------------------------
+    // Validate the API configuration, so that problems show up
+    // now rather than per request. Only unreadable files are fatal.
+    for conf in &api_confs{
+        let api = match API::read_api_file( &conf.api_conf ){
+            Ok( api ) => api,
+            Err( e ) => { error!("{}", e); return Err( e.into() ); }
+        };
+        let mut problems = lint::check_api( &api );
+        problems.append( &mut lint::check_databases( &api, &conf.get_database_names() ) );
+        for problem in problems{
+            match problem.is_error{
+                true => error!("API configuration `{}`: {}", conf.api_conf, problem),
+                false => warn!("API configuration `{}`: {}", conf.api_conf, problem)
+            }
+        }
+    }
 
     // -------------------------------------------------------
     // Set up socket
@@ -454,27 +699,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
     let certificate = Identity::from_pkcs12( &identity, &*pg_api_muscle_config.cert_pass ).expect(&*format!("Constructing certificate from file `{}` using password `{}`", pg_api_muscle_config.cert_file, pg_api_muscle_config.cert_pass)); 
     let tls_acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::builder(certificate).build()?);
 
-    // -------------------------------------------------------
-    // Set up DEADPOOL
-    // See <https://docs.rs/deadpool-postgres/0.7.0/deadpool_postgres/config/struct.Config.html>
-    let mut deadpool_config = Config::new();
-    deadpool_config.dbname = Some(pg_api_muscle_config.db.to_string());
-    deadpool_config.user = Some(pg_api_muscle_config.db_user.to_string());
-    deadpool_config.password = Some(pg_api_muscle_config.db_pass.to_string());
-    deadpool_config.manager = Some(ManagerConfig { recycling_method: RecyclingMethod::Fast });
-
-//    This (below) does not make sure that the timezone is set on all clients;
-//    it may set the timezone on *recycled* clients, but when a new client is
-//    initiated into the pool, GMT is set again. Waiting for a future version 
-//    of tokio for this, cf.
-//    <https://github.com/sfackler/rust-postgres/issues/147#event-4149833164>
-//    cfg.manager = Some(ManagerConfig { recycling_method: RecyclingMethod::Custom(format!("set timezone='Europe/Berlin'")) });
-//    Nor does this work:
-//    cfg.options = Some(format!("-c timezone={}", conf.timezone.to_string()));
-    let pool = deadpool_config.create_pool(NoTls).unwrap();
-
-    // adjust_timezone( &mut pool.get().await.unwrap(), "Europe/Berlin").await;
-    // DEADPOOL END
 
     // time_out specifies when to stop waiting for more
     // input from the socket
@@ -486,21 +710,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
     // API contains basically the main logic, esp. also the
     // routing table. Since the API is handed the request,
     // it needs to be mutable. That's why it is put inside
-    // an async-aware Mutex.
-    let muscle_api = Arc::new(Mutex::new(
-        API::new( &muscle_config.addr, 
-            &muscle_config.token_name, 
-            &muscle_config.pg_setvar_prefix, 
-            &muscle_config.api_conf,
-            muscle_config.use_eq_syntax_on_url_parameters
-        )));
+    // an async-aware Mutex. There is one API per prefix
+    // (see get_api_confs).
+    let mut muscle_apis = vec![];
+    for conf in &api_confs{
+        let muscle_api = Arc::new(Mutex::new( API::new( conf )));
+        muscle_api.lock().await.read_api();
+        tokio::spawn( watch_api( Arc::clone( &muscle_api ), conf.api_watch_interval_ms ));
+        info!("API `{}` at /{}", conf.api_conf, conf.api_prefix);
+        muscle_apis.push( ( Arc::clone( conf ), muscle_api ) );
+    }
+    let muscle_apis = Arc::new( muscle_apis );
+    let api_prefixes: Vec<String> = api_confs.iter().map( |c| c.api_prefix.to_string() ).collect();
 
 
     info!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
     info!("Starting pg_api_muscle service");
     info!("Listening to port {}", muscle_config.port);
     info!("Https? {}", muscle_config.server_use_https);
-    info!("Connected to database: >{}<", muscle_config.db);
+    for db_conf in &muscle_config.databases{
+        info!("Connected to database `{}`: >{}<", db_conf.name, db_conf.db);
+    }
     info!("Restricted to clients from: >{}<", muscle_config.client_ip_allow);
     info!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
 
@@ -510,7 +740,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
         let tls_acceptor = tls_acceptor.clone();
         info!("Accepting connection from {}", remote_addr);
 
-        let api = Arc::clone(&muscle_api);
+        let apis = Arc::clone( &muscle_apis );
+        let api_prefixes = api_prefixes.clone();
 
         // Need the ip address for logging and to make sure
         // that shutdown requests are only executed if they
@@ -526,7 +757,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
 
         // Clone things for the spawned thread:
         let cloned_conf = Arc::clone( &pg_api_muscle_config );
-        let cloned_pool = pool.clone();
+        let cloned_pools = pools.clone();
 
         // Deal with the connection
         tokio::spawn(async move {
@@ -580,6 +811,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
             }
 
             if n == 0 { return; }
+
+            let ( api_conf, api ) = &apis[ get_api_index( &s_request, &api_prefixes ) ];
             
 //            // response is 
 //            //   .0: status + header,
@@ -594,9 +827,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
             //   .0: status + header,
             //   .1: content,
             //   .2: flag for request for static content,
-            let mut response = handle_connection(client_ip, 
-                s_request, &cloned_pool, 
-                &mut api.lock().await.borrow_mut(), &cloned_conf).await;
+            // the client is gone on EOF or if reading fails
+            let mut pipelined: Vec<u8> = vec![];
+            let disconnected = var_stream.wait_for_disconnect( &mut pipelined );
+            let mut response = match handle_connection(client_ip, 
+                s_request, &cloned_pools, 
+                &mut api.lock().await.borrow_mut(), api_conf, disconnected).await{
+                Some( response ) => response,
+                None => return
+            };
 
             let s_status_and_header = response.0; 
             let v_response = &mut s_status_and_header.into_bytes();
@@ -613,10 +852,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
 
             v_response.append( &mut response.1 );
 
-            var_stream
-                .write_all( v_response )
-                .await
-                .expect("failed to write data to socket");
+            if let Err( e ) = var_stream.write_all( v_response ).await {
+                info!("Client disconnected before the response was written: {}", e);
+                return;
+            }
+
+            // one request per connection
+            if !pipelined.is_empty() {
+                warn!("Ignoring {} bytes sent after the request: one request per connection", pipelined.len());
+            }
 
             // @todo: A graceful shutdown would be nicer, but seems connected with 
             // channels or tokio::signal technology, i.e. more complex
@@ -628,26 +872,155 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
     } // LOOP
 }
 
+impl MuscleConfig{
+    fn get_database_names( &self ) -> Vec<String>{
+        self.databases.iter().map( |d| d.name.to_string() ).collect()
+    }
+
+    fn get_database( &self, s_name: &str ) -> Option<&DatabaseConfig>{
+        self.databases.iter().find( |d| d.name == s_name )
+    }
+}
+
+/// One pool per configured database
+fn get_db_pools( pg_api_muscle_config: &MuscleConfig ) -> DbPools{
+    pg_api_muscle_config.databases.iter()
+        .map( |db_conf| ( db_conf.name.to_string(), get_db_pool( db_conf ) ) )
+        .collect()
+}
+
+/// Set up DEADPOOL
+/// See <https://docs.rs/deadpool-postgres/0.7.0/deadpool_postgres/config/struct.Config.html>
+fn get_db_pool( db_conf: &DatabaseConfig ) -> Pool{
+    let mut deadpool_config = Config::new();
+    deadpool_config.dbname = Some(db_conf.db.to_string());
+    deadpool_config.user = Some(db_conf.db_user.to_string());
+    deadpool_config.password = Some(db_conf.db_pass.to_string());
+    deadpool_config.application_name = Some(db_conf.application_name.to_string());
+    // `hosts` rather than `host`: with `host`, deadpool (0.7) adds the default
+    // socket as another host, which tokio_postgres falls back to
+    if db_conf.host != "none" { deadpool_config.hosts = Some(vec![db_conf.host.to_string()]); }
+    deadpool_config.port = db_conf.port;
+    deadpool_config.manager = Some(ManagerConfig { recycling_method: RecyclingMethod::Fast });
+
+    let mut pool_config = PoolConfig::default();
+    if let Some( size ) = db_conf.pool_max_size { pool_config.max_size = size; }
+    pool_config.timeouts = Timeouts{
+        wait: Some( Duration::from_millis( db_conf.pool_wait_timeout_ms ) ),
+        create: db_conf.pool_create_timeout_ms.map( Duration::from_millis ),
+        recycle: db_conf.pool_recycle_timeout_ms.map( Duration::from_millis )
+    };
+    deadpool_config.pool = Some(pool_config);
+
+    // timezone and [Database.session] settings for each new connection
+    deadpool_config.options = Some(get_session_options( db_conf ));
+
+    if db_conf.sslmode == "none" {
+        return deadpool_config.create_pool(NoTls).unwrap();
+    }
+
+    // deadpool (0.7) does not hand ssl_mode on to tokio_postgres, 
+    // so the pool is assembled here
+    let mut pg_config = deadpool_config.get_pg_config().unwrap();
+    pg_config.ssl_mode( match &db_conf.sslmode[..]{
+        "disable" => SslMode::Disable,
+        "prefer" => SslMode::Prefer,
+        "require" => SslMode::Require,
+        x => panic!("Database `{}`: unknown sslmode `{}` (disable, prefer, require)", db_conf.name, x)
+    });
+
+    let manager = Manager::from_config( pg_config, get_tls_connector( db_conf ), deadpool_config.get_manager_config() );
+    Pool::from_config( manager, deadpool_config.get_pool_config() )
+}
+
+///
+/// TLS for the connections to a database (with `sslmode`) -- and for
+/// cancelling their queries
+///
+fn get_tls_connector( db_conf: &DatabaseConfig ) -> MakeTlsConnector{
+    let mut tls_builder = native_tls::TlsConnector::builder();
+    if db_conf.ssl_ca_file != "none" {
+        let ca = std::fs::read( &db_conf.ssl_ca_file ).unwrap_or_else( 
+            |e| panic!("Database `{}`: cannot read ssl_ca_file `{}`: {}", db_conf.name, db_conf.ssl_ca_file, e) );
+        tls_builder.add_root_certificate( Certificate::from_pem( &ca ).unwrap_or_else( 
+            |e| panic!("Database `{}`: ssl_ca_file `{}` is no PEM certificate: {}", db_conf.name, db_conf.ssl_ca_file, e) ));
+    }
+    if db_conf.ssl_cert_file != "none" {
+        let cert = std::fs::read( &db_conf.ssl_cert_file ).unwrap_or_else( 
+            |e| panic!("Database `{}`: cannot read ssl_cert_file `{}`: {}", db_conf.name, db_conf.ssl_cert_file, e) );
+        tls_builder.identity( Identity::from_pkcs12( &cert, &db_conf.ssl_cert_pass ).unwrap_or_else( 
+            |e| panic!("Database `{}`: cannot use ssl_cert_file `{}`: {}", db_conf.name, db_conf.ssl_cert_file, e) ));
+    }
+    MakeTlsConnector::new( tls_builder.build().unwrap_or_else( |e| panic!("Database `{}`: TLS setup failed: {}", db_conf.name, e) ))
+}
+
 ///
 /// Parses the incoming request, 
 /// compares its validity against the API,
 /// rejects the request if it does not conform to the API,
 /// or gets a response from tokio_postgrest as the API specifies.
 ///
-async fn handle_connection(s_client_ip: String, 
+/// If the client disconnects (`disconnected` completes: EOF or
+/// reading from the connection failed) before the 
+/// response is ready, the query is cancelled and None returned.
+///
+async fn handle_connection<F: Future<Output = ()>>(s_client_ip: String, 
     s_request: String, 
-    db_client: &Pool, 
+    db_pools: &DbPools, 
     mut api: &mut API, 
-    conf: &MuscleConfig
-) -> (String, Vec<u8>, bool){
+    conf: &MuscleConfig,
+    disconnected: F
+) -> Option<(String, Vec<u8>, bool)>{
     let request = &mut Request::new( &s_request, 
         &s_client_ip,
         &api.local_ip_address, 
         &conf.token_secret,
         &conf.static_files_folder
      );
+    request.strip_prefix( &conf.api_prefix );
     api.set_request( &request );
-    Response::new( &mut api, db_client, &conf ).await.get_response()
+
+    // The route's database (x-database), or this API's
+    let s_db = api.get_database().unwrap_or( &conf.database ).to_string();
+    if conf.get_database( &s_db ).is_none() {
+        error!("No database named `{}` (x-database) for {}", s_db, api.request.url);
+        return Some( Response::server_error( &format!("No database named `{}`", s_db) ).get_response() );
+    }
+
+    // ... or its replica, for read-only requests
+    let mut db_conf = conf.get_database( &s_db ).unwrap();
+    let mut db_client = &db_pools[ &s_db ];
+    if db_conf.replica != "none" && api.may_use_replica( &conf.primary_header ) {
+        let replica = &db_pools[ &db_conf.replica ];
+        match tokio::time::timeout( Duration::from_millis( db_conf.replica_timeout_ms ), replica.get() ).await{
+            Ok( Ok( _ ) ) => { db_client = replica; db_conf = conf.get_database( &db_conf.replica ).unwrap(); },
+            Ok( Err( e ) ) => warn!("Replica `{}` not available, using `{}`: {}", db_conf.replica, s_db, e),
+            Err( _ ) => warn!("Replica `{}` not available within {}ms, using `{}`", db_conf.replica, db_conf.replica_timeout_ms, s_db)
+        }
+    }
+
+    let cancel_token = Arc::clone( &api.cancel_token );
+    let response = Response::new( &mut api, db_client, &conf );
+    tokio::pin!( response );
+    tokio::select!{
+        response = &mut response => return Some( response.get_response() ),
+        _ = disconnected => {}
+    }
+
+    // The client is gone: cancel the query, but let the response 
+    // finish, so that its transaction ends before the connection
+    // goes back to the pool
+    let token = cancel_token.lock().unwrap().take();
+    if let Some( token ) = token {
+        info!("Client {} disconnected, cancelling the query", s_client_ip);
+        let cancelled = match db_conf.sslmode == "none"{
+            true => token.cancel_query( NoTls ).await,
+            false => token.cancel_query( get_tls_connector( db_conf ) ).await
+        };
+        if let Err( e ) = cancelled { error!("Cancelling the query failed: {}", e); }
+    }
+    response.await;
+    None
 }
 
 // =====================================================================================
@@ -734,20 +1107,19 @@ fn get_conf( s_file: &str ) -> MuscleConfig{
         Err ( e ) => panic!("Configuration file `{}` not found or not accessible: {:?}", s_file, e)
     };
 
-    let s_err = format!("Configuration file `{}` is missing this entry: ", s_file);
+    get_conf_from_ini( &conf, &format!("Configuration file `{}` is missing this entry: ", s_file) )
+}
+
+/// The configuration in `conf`; `s_err` starts the message 
+/// for a missing entry
+fn get_conf_from_ini( conf: &Ini, s_err: &str ) -> MuscleConfig{
 
     MuscleConfig{
-        db: conf.get("Database", "db").expect(
-            &format!("{}{}", s_err, "`db` in section `Database`")[..]),
+        databases: get_database_confs( conf, s_err ),
 
-        db_user: conf.get("Database", "db_user").expect(
-            &format!("{}{}", s_err, "`db_user` in section `Database`")[..]),
-            
-        db_pass: conf.get("Database", "db_pass").expect(
-            &format!("{}{}", s_err, "`db_pass` in section `Database`")[..]),
-            
-        timezone: conf.get("Database", "timezone").expect(
-            &format!("{}{}", s_err, "`timezone` in section `Database`")[..]),
+        database: conf.get("Webservice", "database").unwrap_or_else(|| "default".to_string()),
+
+        primary_header: conf.get("Webservice", "primary_header").unwrap_or_else(|| "X-Use-Primary".to_string()),
 
         port: conf.get("Webservice", "port").expect(
             &format!("{}{}", s_err, "`port` in section `Webservice`")[..]),
@@ -776,6 +1148,10 @@ fn get_conf( s_file: &str ) -> MuscleConfig{
         api_conf: conf.get("Webservice", "api_conf").expect(
             &format!("{}{}", s_err, "`api_conf` in section `Webservice`")[..]),
 
+        api_prefix: "".to_string(),
+
+        api_watch_interval_ms: conf.get("Webservice", "api_watch_interval_ms").unwrap_or(2000),
+
         static_files_folder: conf.get("Webservice", "static_files_folder").expect(
             &format!("{}{}", s_err, "`static_files_folder` in section `Webservice`")[..]),
 
@@ -788,17 +1164,321 @@ fn get_conf( s_file: &str ) -> MuscleConfig{
         pg_setvar_prefix: conf.get("Authorization", "pg_setvar_prefix").expect(
             &format!("{}{}", s_err, "`pg_setvar_prefix` in section `Authorization`")[..]),
 
+        // optional: role switching is off unless configured
+        pg_role_allow: conf.get_vec("Authorization", "pg_role_allow").unwrap_or_default(),
+
+        pg_anon_role: conf.get("Authorization", "pg_anon_role").unwrap_or_else(|| "none".to_string()),
+
+        api_key_header: conf.get("Authorization", "api_key_header").unwrap_or_else(|| "X-Api-Key".to_string()),
+
+        api_key_query_param: conf.get("Authorization", "api_key_query_param").unwrap_or_else(|| "none".to_string()),
+
+        api_key_file: conf.get("Authorization", "api_key_file").unwrap_or_else(|| "none".to_string()),
+
+        api_key_pg_function: conf.get("Authorization", "api_key_pg_function").unwrap_or_else(|| "none".to_string()),
+
+        auth_cookie_name: conf.get("Authorization", "auth_cookie_name").unwrap_or_else(|| "pg_api_muscle_token".to_string()),
+
+        auth_cookie_secure: conf.get("Authorization", "auth_cookie_secure").unwrap_or(true),
+
+        csrf_cookie_name: conf.get("Authorization", "csrf_cookie_name").unwrap_or_else(|| "pg_api_muscle_csrf".to_string()),
+
+        csrf_header: conf.get("Authorization", "csrf_header").unwrap_or_else(|| "X-CSRF-Token".to_string()),
+
+        csrf_allowed_origins: conf.get_vec("Authorization", "csrf_allowed_origins").unwrap_or_default(),
+
+        scope_claim: conf.get("Authorization", "scope_claim").unwrap_or_else(|| "scope".to_string()),
+
         use_eq_syntax_on_url_parameters: conf.get("Service", "api_use_eq_syntax_on_url_parameters").expect(
             &format!("{}{}", s_err, "`api_use_eq_syntax_on_url_parameters` in section `Service`")[..]),
 
         static_404_default: conf.get("Service", "static_404_default").expect(
             &format!("{}{}", s_err, "`static_404_default` in section `Service`")[..]),
 
+        retry_after_s: conf.get("Service", "retry_after_s").unwrap_or(1),
+
+        statement_timeout: conf.get("Service", "statement_timeout").unwrap_or_else(|| "none".to_string()),
+
+        allow_unfiltered: conf.get("Service", "allow_unfiltered").unwrap_or(false),
+
         dynamic_err: conf.get("Service", "dynamic_err").expect(
             &format!("{}{}", s_err, "`dynamic_err` in section `Service`")[..]),
 
         index_file: conf.get("Service", "index_file").expect(
-            &format!("{}{}", s_err, "`index_file` in section `Service`")[..])
+            &format!("{}{}", s_err, "`index_file` in section `Service`")[..]),
+
+        // optional: API documentation is not served unless configured
+        api_doc_path: conf.get("Service", "api_doc_path").unwrap_or_else(|| "none".to_string()),
+
+        api_doc_server_url: conf.get("Service", "api_doc_server_url").unwrap_or_else(|| "none".to_string()),
+
+        api_doc_strip_extensions: conf.get("Service", "api_doc_strip_extensions").unwrap_or(true),
+
+        api_doc_filter_by_token: conf.get("Service", "api_doc_filter_by_token").unwrap_or(true),
+
+        api_explorer_path: conf.get("Service", "api_explorer_path").unwrap_or_else(|| "none".to_string()),
+
+        // optional: no batches unless configured
+        batch_path: conf.get("Service", "batch_path").unwrap_or_else(|| "none".to_string())
+    }
+}
+
+/// The settings of sf_muscle.ini that have no default, for tests
+#[cfg(test)]
+fn get_test_conf() -> MuscleConfig{
+    let ini = Ini::from_string( "[Database]\ndb=test\ndb_user=test\ndb_pass=test\ntimezone=UTC\n\n\
+        [Webservice]\naddr=127.0.0.1\nport=8080\nhttps=false\nclient_ip_allow=0.0.0.0\n\
+        cert_file=identity.pfx\ncert_pass=changeit\nserver_read_timeout_ms=100\nserver_read_chunksize=16384\n\
+        api_conf=none\nstatic_files_folder=static/\n\n\
+        [Authorization]\npg_token_name=request.pg_api_muscle.token\npg_token_secret=geheim\npg_setvar_prefix=pg_api_muscle\n\n\
+        [Service]\napi_use_eq_syntax_on_url_parameters=true\nstatic_404_default=none\ndynamic_err=default\nindex_file=index.html\n" ).unwrap();
+    get_conf_from_ini( &ini, "" )
+}
+
+/// Section `Database` (named "default") and the 
+/// `[Database.<name>]` sections, e.g.
+///
+/// ```
+/// [Database.anglistik]
+/// db=anglistik_ap2
+/// db_user=...
+/// db_pass=...
+/// ```
+///
+/// Keys missing from a named section are taken from section `Database`.
+fn get_database_confs( conf: &Ini, s_err: &str ) -> Vec<DatabaseConfig>{
+    let mut res = vec![ read_database_section( conf, "Database", "default", s_err ) ];
+    for ( s_section, _ ) in conf.iter().filter( |( name, _ )| name.starts_with( "Database." ) && !name.ends_with( ".session" ) ){
+        let db_conf = read_database_section( conf, s_section, &s_section[ "Database.".len().. ], s_err );
+        if res.iter().any( |d: &DatabaseConfig| d.name == db_conf.name ) {
+            panic!("Section `{}`: there is another database named `{}`", s_section, db_conf.name);
+        }
+        res.push( db_conf );
+    }
+
+    for db_conf in &res{
+        if db_conf.replica != "none" && !res.iter().any( |d| d.name == db_conf.replica ) {
+            panic!("Database `{}`: no database named `{}` (replica)", db_conf.name, db_conf.replica);
+        }
+    }
+    res
+}
+
+/// Settings of `[Database.session]` (for named databases: followed 
+/// by those of `[Database.<name>.session]`, which take precedence), e.g.
+///
+/// ```
+/// [Database.session]
+/// search_path=web_api, public
+/// statement_timeout=5s
+/// DateStyle=ISO, DMY
+/// ```
+fn get_session_settings( conf: &Ini, s_section: &str ) -> Vec<(String, String)>{
+    let mut res: Vec<(String, String)> = vec![];
+    let mut sections = vec![ "Database.session".to_string() ];
+    if s_section != "Database" { sections.push( format!("{}.session", s_section) ); }
+    for s_session in sections{
+        for ( key, val ) in conf.section_iter( &s_session ){
+            res.retain( |( k, _ )| !k.eq_ignore_ascii_case( key ) );
+            res.push( ( key.to_string(), val.to_string() ) );
+        }
+    }
+    res
+}
+
+/// Connection options setting the timezone and the session settings
+/// (which are applied by the server when the connection starts, i.e. 
+/// once per connection of the pool): `-c TimeZone=Europe/Berlin -c ...`
+fn get_session_options( db_conf: &DatabaseConfig ) -> String{
+    // spaces and backslashes in values need to be escaped
+    let escape = | s: &str | s.replace( '\\', "\\\\" ).replace( ' ', "\\ " );
+    let mut settings = vec![ ( "TimeZone".to_string(), db_conf.timezone.to_string() ) ];
+    settings.retain( |( k, _ )| !db_conf.session.iter().any( |( key, _ )| key.eq_ignore_ascii_case( k ) ) );
+    settings.extend( db_conf.session.iter().cloned() );
+    settings.iter()
+        .map( |( k, v )| format!("-c {}={}", escape( k ), escape( v )) )
+        .collect::<Vec<String>>()
+        .join( " " )
+}
+
+fn read_database_section( conf: &Ini, s_section: &str, s_name: &str, s_err: &str ) -> DatabaseConfig{
+    // keys missing from a named section are taken from section `Database`
+    fn get<T: std::str::FromStr>( conf: &Ini, s_section: &str, s_key: &str ) -> Option<T>{
+        conf.get( s_section, s_key ).or_else( || conf.get( "Database", s_key ) )
+    }
+    let required = | s_key: &str | -> String {
+        get( conf, s_section, s_key ).unwrap_or_else( || panic!("{}`{}` in section `{}`", s_err, s_key, s_section) )
+    };
+
+    DatabaseConfig{
+        name: s_name.to_string(),
+        db: conf.get( s_section, "db" ).unwrap_or_else( 
+            || panic!("{}`db` in section `{}`", s_err, s_section) ),
+        db_user: required( "db_user" ),
+        db_pass: required( "db_pass" ),
+        timezone: required( "timezone" ),
+        session: get_session_settings( conf, s_section ),
+        replica: conf.get( s_section, "replica" ).unwrap_or_else( || "none".to_string() ),
+        replica_timeout_ms: get( conf, s_section, "replica_timeout_ms" ).unwrap_or( 500 ),
+        host: get( conf, s_section, "host" ).unwrap_or_else( || "none".to_string() ),
+        port: get( conf, s_section, "port" ),
+        sslmode: get( conf, s_section, "sslmode" ).unwrap_or_else( || "none".to_string() ),
+        ssl_ca_file: get( conf, s_section, "ssl_ca_file" ).unwrap_or_else( || "none".to_string() ),
+        ssl_cert_file: get( conf, s_section, "ssl_cert_file" ).unwrap_or_else( || "none".to_string() ),
+        ssl_cert_pass: get( conf, s_section, "ssl_cert_pass" ).unwrap_or_default(),
+        application_name: get( conf, s_section, "application_name" ).unwrap_or_else( || "pg_api_muscle".to_string() ),
+        pool_max_size: get( conf, s_section, "pool_max_size" ),
+        pool_wait_timeout_ms: get( conf, s_section, "pool_wait_timeout_ms" ).unwrap_or( 5000 ),
+        pool_create_timeout_ms: get( conf, s_section, "pool_create_timeout_ms" ),
+        pool_recycle_timeout_ms: get( conf, s_section, "pool_recycle_timeout_ms" )
+    }
+}
+
+/// Configurations of the APIs: the API of section `Webservice`, 
+/// followed by one per `[Api.<name>]` section, e.g.
+///
+/// ```
+/// [Api.v2]
+/// prefix=v2
+/// api_conf=sf_v2.json
+/// pg_token_secret=...
+/// ```
+///
+/// Requests to /v2/... are then handled by the API in sf_v2.json
+/// (as if the prefix was not there). Apart from `prefix` and `api_conf`,
+/// a section can set the keys of sections `Authorization` and 
+/// `Service` that concern the API (see sf_muscle.ini); all others
+/// are taken from the base configuration.
+fn get_api_confs( s_file: &str, base: &MuscleConfig ) -> Vec<MuscleConfig>{
+    let conf = match Ini::from_file( s_file ){
+        Ok( a ) => a,
+        Err ( e ) => panic!("Configuration file `{}` not found or not accessible: {:?}", s_file, e)
+    };
+    read_api_sections( &conf, base, &format!("Configuration file `{}` is missing this entry: ", s_file) )
+}
+
+fn read_api_sections( conf: &Ini, base: &MuscleConfig, s_err: &str ) -> Vec<MuscleConfig>{
+    let mut res = vec![ base.clone() ];
+
+    for ( s_section, _ ) in conf.iter().filter( |( name, _ )| name.starts_with( "Api." ) ){
+        let mut api_conf = base.clone();
+
+        // sets the field if the key is in this section
+        macro_rules! set_if_present{
+            ( $field:ident, $key:expr ) => {
+                if let Some( val ) = conf.get( s_section, $key ) { api_conf.$field = val; }
+            };
+        }
+
+        api_conf.api_prefix = conf.get::<String>( s_section, "prefix" ).unwrap_or_else( 
+            || panic!("{}`prefix` in section `{}`", s_err, s_section) ).trim_matches( '/' ).to_string();
+        api_conf.api_conf = conf.get( s_section, "api_conf" ).unwrap_or_else( 
+            || panic!("{}`api_conf` in section `{}`", s_err, s_section) );
+
+        set_if_present!( api_watch_interval_ms, "api_watch_interval_ms" );
+        set_if_present!( database, "database" );
+        set_if_present!( token_name, "pg_token_name" );
+        set_if_present!( token_secret, "pg_token_secret" );
+        set_if_present!( pg_setvar_prefix, "pg_setvar_prefix" );
+        set_if_present!( pg_anon_role, "pg_anon_role" );
+        set_if_present!( api_key_header, "api_key_header" );
+        set_if_present!( api_key_query_param, "api_key_query_param" );
+        set_if_present!( api_key_file, "api_key_file" );
+        set_if_present!( api_key_pg_function, "api_key_pg_function" );
+        set_if_present!( auth_cookie_name, "auth_cookie_name" );
+        set_if_present!( auth_cookie_secure, "auth_cookie_secure" );
+        set_if_present!( csrf_cookie_name, "csrf_cookie_name" );
+        set_if_present!( csrf_header, "csrf_header" );
+        set_if_present!( scope_claim, "scope_claim" );
+        set_if_present!( dynamic_err, "dynamic_err" );
+        set_if_present!( statement_timeout, "statement_timeout" );
+        set_if_present!( allow_unfiltered, "allow_unfiltered" );
+        set_if_present!( api_doc_path, "api_doc_path" );
+        set_if_present!( api_doc_server_url, "api_doc_server_url" );
+        set_if_present!( api_doc_strip_extensions, "api_doc_strip_extensions" );
+        set_if_present!( api_doc_filter_by_token, "api_doc_filter_by_token" );
+        set_if_present!( api_explorer_path, "api_explorer_path" );
+        set_if_present!( batch_path, "batch_path" );
+        if let Some( v ) = conf.get_vec( s_section, "pg_role_allow" ) { api_conf.pg_role_allow = v; }
+        if let Some( v ) = conf.get_vec( s_section, "csrf_allowed_origins" ) { api_conf.csrf_allowed_origins = v; }
+
+        if res.iter().any( |c: &MuscleConfig| c.api_prefix == api_conf.api_prefix ) {
+            panic!("Section `{}`: prefix `{}` is used by another API", s_section, api_conf.api_prefix);
+        }
+        res.push( api_conf );
+    }
+
+    for api_conf in &res{
+        if !api_conf.get_database_names().contains( &api_conf.database ) {
+            panic!("API `{}`: no database named `{}`", api_conf.api_conf, api_conf.database);
+        }
+    }
+    res
+}
+
+/// Index of the API (see `get_api_confs`) that handles this 
+/// request: the one with the longest prefix matching the path, 
+/// or the API of section `Webservice` (0).
+fn get_api_index( s_request: &str, prefixes: &[String] ) -> usize{
+    let s_path = s_request.lines().next()
+        .and_then( |l| l.split_whitespace().nth( 1 ) ).unwrap_or( "" )
+        .trim_start_matches( '/' );
+    let s_path = s_path.split( '?' ).next().unwrap_or( "" );
+    prefixes.iter().enumerate()
+        .filter( |( _, p )| !p.is_empty() && ( s_path == *p || s_path.starts_with( &format!("{}/", p) ) ) )
+        .max_by_key( |( _, p )| p.len() )
+        .map( |( i, _ )| i )
+        .unwrap_or( 0 )
+}
+
+#[cfg(test)]
+mod test_api_confs{
+    use super::*;
+
+    #[test]
+    fn sections() {
+        let base = get_test_conf();
+        let ini = Ini::from_string( "[Api.v2]\nprefix=/v2/\napi_conf=v2.json\npg_token_secret=geheim\ndynamic_err=none\n\n[Other]\nx=1\n" ).unwrap();
+        let confs = read_api_sections( &ini, &base, "" );
+        assert_eq!( confs.len(), 2 );
+        assert_eq!( confs[0].api_prefix, "" );
+        assert_eq!( confs[1].api_prefix, "v2" );
+        assert_eq!( confs[1].api_conf, "v2.json" );
+        assert_eq!( confs[1].token_secret, "geheim" );
+        assert_eq!( confs[1].dynamic_err, "none" );
+        assert_eq!( confs[1].token_name, base.token_name );
+    }
+
+    #[test]
+    fn databases() {
+        let ini = Ini::from_string( "[Database]\ndb=a\ndb_user=u\ndb_pass=p\ntimezone=Europe/Berlin\n\n[Database.zwei]\ndb=b\ndb_user=v\n" ).unwrap();
+        let dbs = get_database_confs( &ini, "" );
+        assert_eq!( dbs.len(), 2 );
+        assert_eq!( dbs[0].name, "default" );
+        assert_eq!( dbs[1].name, "zwei" );
+        assert_eq!( dbs[1].db, "b" );
+        assert_eq!( dbs[1].db_user, "v" );
+        assert_eq!( dbs[1].db_pass, "p" );
+    }
+
+    #[test]
+    fn session() {
+        let ini = Ini::from_string( "[Database]\ndb=a\ndb_user=u\ndb_pass=p\ntimezone=Europe/Berlin\n\n[Database.session]\nsearch_path=web_api, public\nstatement_timeout=5s\n\n[Database.zwei]\ndb=b\n\n[Database.zwei.session]\nstatement_timeout=1s\ntimezone=UTC\n" ).unwrap();
+        let dbs = get_database_confs( &ini, "" );
+        assert_eq!( dbs.len(), 2 );
+        assert_eq!( get_session_options( &dbs[0] ), "-c TimeZone=Europe/Berlin -c search_path=web_api,\\ public -c statement_timeout=5s" );
+        assert_eq!( get_session_options( &dbs[1] ), "-c search_path=web_api,\\ public -c statement_timeout=1s -c timezone=UTC" );
+    }
+
+    #[test]
+    fn api_index() {
+        let prefixes = vec!["".to_string(), "v2".to_string(), "v2/beta".to_string()];
+        assert_eq!( get_api_index( "GET /kapitel?a=b HTTP/1.1\r\n", &prefixes ), 0 );
+        assert_eq!( get_api_index( "GET /v2/kapitel?a=b HTTP/1.1\r\n", &prefixes ), 1 );
+        assert_eq!( get_api_index( "GET /v2?a=b HTTP/1.1\r\n", &prefixes ), 1 );
+        assert_eq!( get_api_index( "GET /v2/beta/kapitel HTTP/1.1\r\n", &prefixes ), 2 );
+        assert_eq!( get_api_index( "GET /v20/kapitel HTTP/1.1\r\n", &prefixes ), 0 );
+        assert_eq!( get_api_index( "", &prefixes ), 0 );
     }
 }
 
@@ -857,4 +1537,41 @@ mod test_query_parameters{
         let t=UnCheckedParam::new_query_parameter_ext("test", "eq.a8", ParameterType::BIGINT);
         assert_eq!(t.is_conform(), false);
     }
+
+    #[test]
+    fn payload() {
+        let v: Value = serde_json::from_str( r#"{"toc": [{"titel": "Eins"}], "buch": {"id": 1}}"# ).unwrap();
+        let t=UnCheckedParam::new_payload_parameter("toc", v.get("toc"), ParameterType::ARRAY, true);
+        assert!(t.is_conform());
+        assert_eq!(t.value, ParamVal::Text( r#"[{"titel":"Eins"}]"#.to_string() ));
+
+        let t=UnCheckedParam::new_payload_parameter("buch", v.get("buch"), ParameterType::OBJECT, true);
+        assert!(t.is_conform());
+
+        let t=UnCheckedParam::new_payload_parameter("toc", v.get("toc"), ParameterType::OBJECT, true);
+        assert!(!t.is_conform());
+    }
+}
+
+#[cfg(test)]
+mod test_disconnect{
+    use super::*;
+
+    #[tokio::test]
+    async fn client_closes_mid_query() {
+        let listener = TcpListener::bind( "127.0.0.1:0" ).await.unwrap();
+        let mut client = TcpStream::connect( listener.local_addr().unwrap() ).await.unwrap();
+        let mut var_stream = VarStream::Insecure( listener.accept().await.unwrap().0 );
+        client.write_all( &[b'x'; MAX_PIPELINED + 100] ).await.unwrap();
+        drop( client );
+
+        let mut pipelined: Vec<u8> = vec![];
+        let query = tokio::time::sleep( Duration::from_secs( 10 ) );
+        let cancelled = tokio::select!{
+            _ = query => false,
+            _ = var_stream.wait_for_disconnect( &mut pipelined ) => true
+        };
+        assert!( cancelled );
+        assert_eq!( pipelined.len(), MAX_PIPELINED );
+    }
 }
@@ -3,6 +3,7 @@ use jwt_simple::prelude::*;
 use serde_json::Value;
 use crate::RequestMethod;
 use crate::Authentication;
+use crate::AuthMethod;
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Request {
@@ -14,8 +15,10 @@ pub struct Request {
     query_params: Vec<(String,String)>,
     content_type: String,
     authorization: String,
+    headers: Vec<(String, String)>,     // header names in lower case
     pub auth_claim: Option<Value>,
     pub api_needs_auth: Authentication,
+    pub auth_method: AuthMethod,
     pub token_secret: String,
     pub static_folder: String,
     pub method: RequestMethod,
@@ -103,10 +106,12 @@ impl Request{
             method_reroute: RequestMethod::UNKNOWN,
             content_type: ct_payload_auth.0.to_string(),
             authorization: ct_payload_auth.2.to_string(),
+            headers: Request::get_headers( s_req ),
             auth_claim: claims,
             is_shutdown: b_is_request_for_shutdown,
             is_reload_config: b_is_request_for_api_reload,
             api_needs_auth: Authentication::UNKNOWN,
+            auth_method: AuthMethod::NONE,
             token_secret: token_secret.to_string(),
             static_folder: static_folder.to_string(),
             ip_address: s_ip_addr_client.to_string(),
@@ -156,6 +161,40 @@ impl Request{
         self.get_payload_params_as_value().get( s_name )
     }
 
+    /// Removes the path prefix of the API (see `[Api.*]` sections
+    /// in the .ini file), so that the url matches the paths in its OpenAPI file:
+    /// prefix `v2` turns `v2/kapitel` into `kapitel`.
+    pub fn strip_prefix( &mut self, s_prefix: &str ){
+        if s_prefix.is_empty() { return; }
+        if let Some( s_rest ) = self.url.strip_prefix( s_prefix ){
+            if s_rest.is_empty() || s_rest.starts_with( '/' ) {
+                self.url = s_rest.trim_start_matches( '/' ).to_string();
+            }
+        }
+    }
+
+    /// An operation of a batch request (see Response::handle_batch): a
+    /// request with the headers and the authentication of this one, but
+    /// with its own method, path, query and payload. The conditions and
+    /// preferences of the batch request are not the operation's.
+    pub fn get_operation( &self, s_method: &str, s_path: &str, s_query: &str, s_payload: &str ) -> Request{
+        let mut op = self.clone();
+        op.headers.retain( |h| !["if-match", "if-none-match", "prefer"].contains( &&h.0[..] ) );
+        op.method = Request::get_method( &format!("{} /", s_method) );
+        op.method_reroute = RequestMethod::UNKNOWN;
+        op.url = s_path.trim_start_matches( '/' ).to_string();
+        op.q_parms = s_query.trim_start_matches( '?' ).to_string();
+        op.query_params = vec![];
+        op.query_is_read = false;
+        op.p_parms = s_payload.to_string();
+        op.payload = Value::Null;
+        op.payload_is_read = false;
+        op.api_needs_auth = Authentication::UNKNOWN;
+        op.is_shutdown = false;
+        op.is_reload_config = false;
+        op
+    }
+
     /// Is this a request for a static page?
     pub fn is_static( &self ) -> bool {
         self.url.starts_with( &self.static_folder )
@@ -166,6 +205,38 @@ impl Request{
         &self.authorization
     }
 
+    /// Value of a header (name is case insensitive), e.g.
+    /// `request.get_header( "x-api-key" )`
+    pub fn get_header( &self, s_name: &str ) -> Option<&str>{
+        let s_name = s_name.to_lowercase();
+        self.headers.iter().find( |h| h.0 == s_name ).map( |h| &h.1[..] )
+    }
+
+    /// Authenticate with an API key rather than with the bearer token
+    /// (see API::check_auth_need); claims are those configured for the key.
+    pub fn set_api_key_auth( &mut self, s_key: &str, claims: Option<Value> ){
+        self.auth_method = AuthMethod::APIKEY;
+        self.authorization = s_key.to_string();
+        self.auth_claim = claims;
+    }
+
+    /// Authenticate with a JWT from a cookie rather than with the 
+    /// bearer token (see API::check_auth_need)
+    pub fn set_cookie_auth( &mut self, s_token: &str ){
+        self.auth_method = AuthMethod::COOKIE;
+        self.authorization = s_token.to_string();
+        self.auth_claim = Request::get_auth_claims( s_token.to_string(), self.token_secret.to_string() );
+    }
+
+    /// Value of a cookie in the `Cookie` header
+    pub fn get_cookie( &self, s_name: &str ) -> Option<&str>{
+        self.get_header( "cookie" )?
+            .split(';')
+            .filter_map( |c| c.trim().split_once('=') )
+            .find( |c| c.0 == s_name )
+            .map( |c| c.1 )
+    }
+
     pub fn has_valid_auth( &self ) -> bool{
         match self.auth_claim{
             Some ( _ ) => true,
@@ -269,6 +340,16 @@ impl Request{
         ( s_content_type, s_last, s_authorization )
     }
 
+    /// Static method: headers of the request as (lower case name, value),
+    /// i.e. all lines after the first up to the first empty line.
+    fn get_headers( s_req: &str ) -> Vec<(String, String)>{
+        s_req.lines().skip(1)
+            .take_while( |line| !line.trim().is_empty() )
+            .filter_map( |line| line.split_once(':') )
+            .map( |(name, val)| (name.trim().to_lowercase(), val.trim().to_string()) )
+            .collect()
+    }
+
     pub fn get_method_as_str( method: RequestMethod ) -> &'static str{
         match method{
             RequestMethod::GET => "get",
@@ -292,4 +373,45 @@ mod test_get_query{
         assert_eq!( Request::get_url_plus_parms("Whatever?this=that&a=b").1, "this=that&a=b" );
 
     }
+
+    #[test]
+    fn test_get_header() {
+        let r = Request::new( "GET /kapitel HTTP/1.1\r\nHost: localhost\r\nX-Api-Key: abc:def\r\n\r\n{\"a\":1}", "::1", "127.0.0.1", "", "static" );
+        assert_eq!( r.get_header( "x-api-key" ), Some("abc:def") );
+        assert_eq!( r.get_header( "Host" ), Some("localhost") );
+        assert_eq!( r.get_header( "{\"a\"" ), None );
+    }
+
+    #[test]
+    fn test_get_cookie() {
+        let r = Request::new( "GET /kapitel HTTP/1.1\r\nCookie: a=1; pg_api_muscle_token=ey.x=; b=\r\n\r\n", "::1", "127.0.0.1", "", "static" );
+        assert_eq!( r.get_cookie( "pg_api_muscle_token" ), Some("ey.x=") );
+        assert_eq!( r.get_cookie( "a" ), Some("1") );
+        assert_eq!( r.get_cookie( "b" ), Some("") );
+        assert_eq!( r.get_cookie( "c" ), None );
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let mut r = Request::new( "GET /v2/kapitel?a=b HTTP/1.1\r\n\r\n", "::1", "127.0.0.1", "", "static" );
+        r.strip_prefix( "v20" );
+        assert_eq!( r.url, "v2/kapitel" );
+        r.strip_prefix( "v2" );
+        assert_eq!( r.url, "kapitel" );
+    }
+
+    #[test]
+    fn test_get_operation() {
+        let r = Request::new( "POST /v2/batch HTTP/1.1\r\nX-Api-Key: abc:def\r\nIf-Match: *\r\nPrefer: return=minimal\r\n\r\n[]", "::1", "127.0.0.1", "", "static" );
+        let mut op = r.get_operation( "patch", "/v2/kapitel", "?id=eq.3", "{\"titel\":\"x\"}" );
+        op.strip_prefix( "v2" );
+        assert_eq!( op.method, RequestMethod::PATCH );
+        assert_eq!( op.url, "kapitel" );
+        assert_eq!( op.get_query_parameter_value( "id" ), Some("eq.3") );
+        assert_eq!( op.get_payload_param( "titel" ), Some(&Value::from("x")) );
+        assert_eq!( op.get_header( "x-api-key" ), Some("abc:def") );
+        assert_eq!( op.get_header( "if-match" ), None );
+        assert_eq!( op.get_header( "prefer" ), None );
+        assert_eq!( r.get_operation( "put", "kapitel", "", "" ).method, RequestMethod::UNKNOWN );
+    }
 }
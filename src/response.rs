@@ -3,6 +3,15 @@ use crate::Request;
 use crate::RequestMethod;
 use deadpool_postgres::{Pool };
 use crate::db::get_db_response;
+use crate::db::get_api_key_claims;
+use crate::db::{begin_batch, end_batch, get_db_batch_operation_response};
+use crate::db::ERR_NO_DB_CLIENT;
+use crate::db::ERR_NO_ROWS;
+use crate::db::ERR_PRECONDITION;
+use crate::db::ERR_ANON_ROLE;
+use crate::AuthMethod;
+use crate::ReturnPreference;
+use crate::S_EMPTY;
 use crate::API;
 use log::{error, info};
 use std::io::prelude::*; // needed for read_do_end
@@ -14,6 +23,40 @@ struct APIError {
     hint: String
 }
 
+/// One operation of a batch request (see Response::handle_batch)
+#[derive(Deserialize, Debug)]
+struct BatchOperation {
+    method: String,
+    path: String,
+    #[serde(default)]
+    query: serde_json::Value,   // "id=eq.3" or {"id": "eq.3"}
+    #[serde(default)]
+    body: serde_json::Value
+}
+
+impl BatchOperation{
+
+    /// The query as in a url
+    fn get_query( &self ) -> String{
+        match &self.query{
+            serde_json::Value::Null => S_EMPTY,
+            serde_json::Value::String( s ) => s.to_string(),
+            serde_json::Value::Object( o ) => serde_urlencoded::to_string( o.iter()
+                .map( |(k, v)| ( k, v.as_str().map( |s| s.to_string() ).unwrap_or_else( || v.to_string() ) ) )
+                .collect::<Vec<_>>() ).unwrap_or( S_EMPTY ),
+            q => q.to_string()
+        }
+    }
+
+    /// The body as in a request
+    fn get_body( &self ) -> String{
+        match &self.body{
+            serde_json::Value::Null => S_EMPTY,
+            b => b.to_string()
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Response {
     pub http_status: String,
@@ -26,16 +69,31 @@ impl Response{
 
     const HTTP_404: &'static str = "HTTP/1.1 404 NOT FOUND";
     const HTTP_400: &'static str = "HTTP/1.1 400 BAD REQUEST";
+    const HTTP_403: &'static str = "HTTP/1.1 403 FORBIDDEN";
+    const HTTP_500: &'static str = "HTTP/1.1 500 INTERNAL SERVER ERROR";
+    const HTTP_503: &'static str = "HTTP/1.1 503 SERVICE UNAVAILABLE";
     const HTTP_200: &'static str = "HTTP/1.1 200 OK";
+    const HTTP_201: &'static str = "HTTP/1.1 201 CREATED";
+    const HTTP_204: &'static str = "HTTP/1.1 204 NO CONTENT";
+    const HTTP_304: &'static str = "HTTP/1.1 304 NOT MODIFIED";
+    const HTTP_412: &'static str = "HTTP/1.1 412 PRECONDITION FAILED";
 
     const CONTENT_TYPE_JSON: &'static str = "application/json;charset=UTF-8";
     const CONTENT_TYPE_HTML: &'static str = "text/html;charset=UTF-8";
 
+    // Page to explore the OpenAPI document (.ini: api_explorer_path)
+    const API_EXPLORER: &'static str = include_str!("explorer.html");
+
     fn is_error( HTTP_status: &str ) -> bool{
         match HTTP_status{
             Response::HTTP_404 => true,
             Response::HTTP_400 => true,
+            Response::HTTP_403 => true,
+            Response::HTTP_503 => true,
             Response::HTTP_200 => false,
+            Response::HTTP_201 => false,
+            Response::HTTP_204 => false,
+            Response::HTTP_304 => false,
             _ => true
         }
     }
@@ -50,9 +108,22 @@ impl Response{
             api.request.q_parms, 
             api.request.p_parms.chars().take(80).collect::<String>());
         
+        Response::set_api_key_claims( api, client ).await;
+
+        let b_is_api_doc = api.request.method == RequestMethod::GET && conf.api_doc_path != "none" 
+            && api.request.url == conf.api_doc_path;
+        let b_is_api_explorer = api.request.method == RequestMethod::GET && conf.api_explorer_path != "none" 
+            && api.request.url == conf.api_explorer_path;
+        let b_is_batch = api.request.method == RequestMethod::POST && conf.batch_path != "none" 
+            && api.request.url == conf.batch_path;
+
         let mut s_resp = match api.request.method{
+            RequestMethod::GET if b_is_api_doc => ( Response::HTTP_200.to_string(), api.get_api_doc( conf ).to_string().into_bytes() ),
+            RequestMethod::GET if b_is_api_explorer => ( Response::HTTP_200.to_string(), 
+                Response::get_api_explorer( conf ).into_bytes() ),
             RequestMethod::GET =>  Response::handle_get( api, client, &conf).await,
             RequestMethod::DELETE => Response::handle_delete( api, client ).await,
+            RequestMethod::POST if b_is_batch => Response::handle_batch( api, client, conf ).await,
             RequestMethod::POST => Response::handle_post( api, client).await,
             RequestMethod::PATCH => Response::handle_patch( api, client).await,
             _ => ( Response::HTTP_404.to_string(), b"Method not implemented".to_vec() )
@@ -68,11 +139,78 @@ impl Response{
         // (2) Access-Control etc. erlaubt Anfragen von Skripts anderer Seiten (man kann da
         // (3) Mime-Guess muss expandiert werden auf andere Typen als nur png.
         // spezifizieren!)
+        // functions may return something else than JSON (x-content-type),
+        // but errors are JSON
+        let content_type = api.get_content_type().filter( |_| !Response::is_error(&s_resp.0[..]) )
+            .unwrap_or( Response::CONTENT_TYPE_JSON ).to_string();
         let mut header = match api.request.is_static() {
             true => Response::get_mime_guess( &api.request.url ),
-            _ => format!("{}\r\n{}\r\n", Response::CONTENT_TYPE_JSON, content_type_header) // "Content-Type: application/json;charset=UTF-8\r\nAccess-Control-Allow-Origin: *\r\n".to_string()
+            _ if b_is_api_explorer => format!("Content-Type: {}\r\n", Response::CONTENT_TYPE_HTML),
+            _ => format!("Content-Type: {}\r\n{}\r\n", content_type, content_type_header) // "Content-Type: application/json;charset=UTF-8\r\nAccess-Control-Allow-Origin: *\r\n".to_string()
         };
 
+        // A login route can hand its token to the browser as an HttpOnly
+        // cookie (for x-auth-method: cookie_jwt), together with a CSRF token
+        if !Response::is_error(&s_resp.0[..]) && !api.request.is_static() {
+            if let Some( s_field ) = api.get_route_property( "x-set-auth-cookie" ).as_str().map( |f| f.to_string() ){
+                header.push_str( &Response::get_auth_cookie_headers( &s_resp.1, &s_field, conf ) );
+            }
+        }
+
+        // ETag of what GET returns (If-None-Match: 304 if the client 
+        // has it already), and of a single row that PATCH returns
+        if !Response::is_error(&s_resp.0[..]) && !api.request.is_static() && !b_is_api_doc && !b_is_api_explorer {
+            let etag = match api.request.method{
+                RequestMethod::GET => Some( Response::get_version_etag( api, &s_resp.1 ).unwrap_or_else( || Response::get_hash_etag( &s_resp.1 ) ) ),
+                RequestMethod::PATCH => Response::get_version_etag( api, &s_resp.1 ),
+                _ => None
+            };
+            if let Some( s_etag ) = etag{
+                if api.request.method == RequestMethod::GET 
+                    && api.request.get_header( "If-None-Match" ).is_some_and( |h| Response::etag_matches( h, &s_etag ) ) {
+                    s_resp = ( Response::HTTP_304.to_string(), vec![] );
+                }
+                header.push_str( &format!("ETag: {}\r\n", s_etag) );
+            }
+        }
+
+        // Inserts are 201 Created, with the Location of the new row -- if 
+        // a row came back (row level security may hide it: 204, or 200 
+        // with an empty array for return=representation).
+        // Prefer: return=minimal or headers-only drop the content
+        if !Response::is_error(&s_resp.0[..]) && !api.request.is_static() && !b_is_batch {
+            let preference = api.get_return_preference();
+            let row = match api.is_insert(){
+                true => serde_json::from_slice( &s_resp.1 ).unwrap_or( serde_json::Value::Null ),
+                false => serde_json::Value::Null
+            };
+            let b_created = Response::has_row( &row );
+            if b_created {
+                match api.get_location( &row, &conf.api_prefix ){
+                    Some( s_location ) => header.push_str( &format!("Location: {}\r\n", s_location) ),
+                    None if preference == ReturnPreference::HEADERSONLY => 
+                        error!("No Location for the new row: the route needs x-primary-key, and a GET route for `{}`", api.request.url),
+                    None => {}
+                }
+                s_resp.0 = Response::HTTP_201.to_string();
+            }
+            match preference{
+                ReturnPreference::MINIMAL | ReturnPreference::HEADERSONLY if b_created => s_resp.1 = vec![],
+                ReturnPreference::REPRESENTATION => {},
+                _ if api.is_insert() && !b_created => s_resp = ( Response::HTTP_204.to_string(), vec![] ),
+                ReturnPreference::MINIMAL => s_resp = ( Response::HTTP_204.to_string(), vec![] ),
+                _ => {}
+            }
+            if let Some( applied ) = api.get_applied_preference() {
+                header.push_str( &format!("Preference-Applied: return={}\r\n", applied.as_str()) );
+            }
+        }
+
+        // Clients may try again when a connection is free
+        if s_resp.0 == Response::HTTP_503 {
+            header.push_str( &format!("Retry-After: {}\r\n", conf.retry_after_s) );
+        }
+
         if Response::is_error(&s_resp.0[..]){
 
             // static requests already are taken care
@@ -114,6 +252,28 @@ impl Response{
         }
     }
 
+    /// 500 with a JSON error, for requests that cannot be
+    /// handled because of the configuration (e.g. an unknown `x-database`)
+    pub fn server_error( s_msg: &str ) -> Self {
+        Self{
+            http_status: Response::HTTP_500.to_string(),
+            http_content: serde_json::to_string( &APIError{ message: s_msg.to_string(), 
+                hint: "Check the configuration".to_string()} ).unwrap().into_bytes(),
+            content_type_header: format!("Content-Type: {}\r\n", Response::CONTENT_TYPE_JSON),
+            is_static: false
+        }
+    }
+
+    /// API keys that are checked by the database need
+    /// their claims before the request is checked against the api
+    async fn set_api_key_claims( api: &mut API, client: &Pool ){
+        if api.request.auth_method == AuthMethod::APIKEY && !api.request.get_auth().is_empty() {
+            if let Some( s_function ) = api.get_api_key_pg_function().map( |f| f.to_string() ){
+                api.request.auth_claim = get_api_key_claims( client, &s_function, &api.request.get_auth().clone() ).await;
+            }
+        }
+    }
+
     // Extend with .js, .css, jpg, jpeg, mp3, mpeg
     fn get_mime_guess( url:&String ) -> String{
         
@@ -132,6 +292,184 @@ impl Response{
 //        (format!( "{}\r\n{}\r\n", self.http_status, self.content_type_header), self.http_content, self.is_static)
     }
 
+    /// Set-Cookie headers for the token in field `s_field` of the JSON
+    /// response (or of its first element, if it is an array), plus a
+    /// random CSRF token that scripts can read and repeat in a header.
+    /// Both are `Secure` unless auth_cookie_secure is false.
+    ///
+    /// Empty if the response contains no such token.
+    fn get_auth_cookie_headers( content: &[u8], s_field: &str, conf: &MuscleConfig ) -> String{
+        let json: serde_json::Value = serde_json::from_slice( content ).unwrap_or( serde_json::Value::Null );
+        let token = match json.as_array(){
+            Some( rows ) => rows.first().and_then( |r| r.get( s_field ) ),
+            None => json.get( s_field )
+        };
+        let s_token = match token.and_then( |t| t.as_str() ){
+            Some( t ) => t,
+            None => { error!("x-set-auth-cookie: response has no token in field `{}`", s_field); return S_EMPTY; }
+        };
+
+        let s_secure = if conf.auth_cookie_secure { " Secure;" } else { "" };
+        let mut s_headers = format!("Set-Cookie: {}={}; Path=/; HttpOnly;{} SameSite=Strict\r\n", conf.auth_cookie_name, s_token, s_secure);
+        if conf.csrf_cookie_name != "none" {
+            let mut csrf = [0u8; 16];
+            match getrandom::getrandom( &mut csrf ){
+                Ok( _ ) => s_headers.push_str( &format!("Set-Cookie: {}={}; Path=/;{} SameSite=Strict\r\n", 
+                    conf.csrf_cookie_name, csrf.iter().map( |b| format!("{:02x}", b) ).collect::<String>(), s_secure) ),
+                Err( e ) => error!("No random CSRF token available: {}", e)
+            }
+        }
+        s_headers
+    }
+
+    /// Did an insert return a row: an object with columns, or a 
+    /// non-empty array (see get_returning_sql)?
+    fn has_row( row: &serde_json::Value ) -> bool{
+        match row{
+            serde_json::Value::Object( o ) => !o.is_empty(),
+            serde_json::Value::Array( a ) => !a.is_empty(),
+            _ => false
+        }
+    }
+
+    /// ETag of a response: FNV-1a hash (64 bit) of its content, 
+    /// prefixed with `h-` to tell it from a version (see is_hash_etag)
+    pub fn get_hash_etag( content: &[u8] ) -> String{
+        let hash = content.iter().fold( 0xcbf29ce484222325u64, |h, b| ( h ^ *b as u64 ).wrapping_mul( 0x100000001b3 ) );
+        format!("\"h-{:016x}\"", hash)
+    }
+
+    /// Is the ETag (weak or not) one of get_hash_etag, rather 
+    /// than a version (see get_version_etag)?
+    pub fn is_hash_etag( s_etag: &str ) -> bool{
+        let s_etag = s_etag.trim().trim_start_matches( "W/" ).trim_matches( '"' );
+        s_etag.len() == 18 && s_etag.starts_with( "h-" ) && s_etag[ 2.. ].chars().all( |c| c.is_ascii_hexdigit() )
+    }
+
+    /// ETag of a response with a single row: the value of its 
+    /// `x-version-column` (None for other responses)
+    fn get_version_etag( api: &API, content: &[u8] ) -> Option<String>{
+        let s_column = api.get_version_column()?;
+        let json: serde_json::Value = serde_json::from_slice( content ).ok()?;
+        let row = match json.as_array(){
+            Some( rows ) if rows.len() == 1 => &rows[0],
+            Some( _ ) => return None,
+            None => &json
+        };
+        match &row[ s_column ]{
+            serde_json::Value::Null => None,
+            serde_json::Value::String( s ) => Some( format!("\"{}\"", s) ),
+            v => Some( format!("\"{}\"", v) )
+        }
+    }
+
+    /// Does the ETag match one in the header If-Match or If-None-Match
+    /// (a list of ETags, or `*`)? Weak ETags (`W/"..."`) count.
+    pub fn etag_matches( s_header: &str, s_etag: &str ) -> bool{
+        s_header.split( ',' ).map( |t| t.trim() ).any( |t| t == "*" || t.trim_start_matches( "W/" ) == s_etag )
+    }
+
+    /// Status for a request that deviates from the api: failed
+    /// claim checks are 403, everything else is a bad request.
+    fn get_deviation_status( api: &API ) -> String{
+        match api.deviation_is_forbidden{
+            true => Response::HTTP_403.to_string(),
+            false => Response::HTTP_400.to_string()
+        }
+    }
+
+    /// Status for an error from the database: 503 if there was no
+    /// connection (the pool is exhausted or the database unavailable),
+    /// 404 if a PATCH or DELETE matched no row, 412 if they have 
+    /// changed (If-Match), 500 if the anonymous role cannot be used, 
+    /// otherwise a bad request.
+    fn get_db_error_status( s_err: &str ) -> String{
+        if s_err.starts_with( ERR_NO_DB_CLIENT ) { return Response::HTTP_503.to_string(); }
+        if s_err.starts_with( ERR_ANON_ROLE ) { return Response::HTTP_500.to_string(); }
+        match s_err{
+            ERR_NO_ROWS => Response::HTTP_404.to_string(),
+            ERR_PRECONDITION => Response::HTTP_412.to_string(),
+            _ => Response::HTTP_400.to_string()
+        }
+    }
+
+    /// Runs the operations of a batch (a JSON array of `{"method", 
+    /// "path", "query", "body"}` POSTed to batch_path) in order and in
+    /// one transaction. Each is checked against the api like a request
+    /// of its own, with the headers of the batch request.
+    ///
+    /// Returns .1 status and headers, .2 the array of the operations'
+    /// results -- or the error of the first one that fails, in which 
+    /// case none of them has changed anything.
+    async fn handle_batch( api: &mut API, client: &Pool, conf: &MuscleConfig ) -> (String, Vec<u8>){
+        let batch_request = api.request.clone();
+        let s_resp = Response::run_batch( api, client, conf, &batch_request ).await;
+        // the response is about the batch, not its last operation
+        api.set_request( &batch_request );
+        s_resp
+    }
+
+    /// Returns .1 status and headers, .2 content (see handle_batch)
+    async fn run_batch( api: &mut API, client: &Pool, conf: &MuscleConfig, batch_request: &Request ) -> (String, Vec<u8>){
+        let operations: Vec<BatchOperation> = match serde_json::from_str( &batch_request.p_parms ){
+            Ok( ops ) => ops,
+            Err( e ) => {error!("... bad batch request: {}", e);
+                return (Response::HTTP_400.to_string(), serde_json::to_string( 
+                    &APIError{ message: format!("A batch is an array of {{\"method\", \"path\", \"query\", \"body\"}}: {}", e), 
+                        hint: "No hint".to_string()}).unwrap().as_bytes().to_vec()) }
+        };
+
+        let mut db_client = match begin_batch( client, api ).await{
+            Ok( c ) => c,
+            Err( e ) => {error!("...db problem on batch: {}", e);
+                return (Response::get_db_error_status( &e ), serde_json::to_string( 
+                    &APIError{ message: e, hint: "No hint".to_string()}).unwrap().as_bytes().to_vec()) }
+        };
+
+        let mut results = vec![];
+        for ( i, op ) in operations.iter().enumerate(){
+            let mut request = batch_request.get_operation( &op.method, &op.path, &op.get_query(), &op.get_body() );
+            request.strip_prefix( &conf.api_prefix );
+            api.set_request( &request );
+            Response::set_api_key_claims( api, client ).await;
+
+            let result = match ( &api.get_request_deviation( )[..], Response::get_batch_database_problem( api, conf ) ){
+                ( "", None ) => get_db_batch_operation_response( &mut db_client, api ).await
+                    .map_err( |e| ( Response::get_db_error_status( &e ), e ) ),
+                ( "", Some( e ) ) => Err( ( Response::HTTP_400.to_string(), e ) ),
+                ( x, _ ) => Err( ( Response::get_deviation_status( api ), x.to_string() ) )
+            };
+            match result{
+                Ok( s ) => results.push( s ),
+                Err( ( s_status, e ) ) => {
+                    if let Err( e_end ) = end_batch( &mut db_client, false ).await { error!("...db problem ending batch: {}", e_end); }
+                    error!("... batch operation {} ({} {}) failed, nothing changed: {}", i, op.method, op.path, e);
+                    return (s_status, serde_json::to_string( 
+                        &APIError{ message: format!("Operation {} ({} {}): {}", i, op.method, op.path, e), 
+                            hint: "No hint".to_string()}).unwrap().as_bytes().to_vec())
+                }
+            }
+        }
+
+        match end_batch( &mut db_client, true ).await{
+            Ok( _ ) => (Response::HTTP_200.to_string(), format!("[{}]", results.join(",")).into_bytes()),
+            Err( e ) => {error!("...db problem on batch: {}", e);
+                (Response::get_db_error_status( &e ), serde_json::to_string( 
+                    &APIError{ message: e, hint: "No hint".to_string()}).unwrap().as_bytes().to_vec()) }
+        }
+    }
+
+    /// A batch runs in one transaction on the primary of the API's 
+    /// database: operations of routes with another `x-database` cannot
+    /// be part of it
+    fn get_batch_database_problem( api: &API, conf: &MuscleConfig ) -> Option<String>{
+        match api.get_database(){
+            Some( s_db ) if s_db != conf.database => 
+                Some( format!("Batches run on database `{}`, the route uses `{}` (x-database)", conf.database, s_db) ),
+            _ => None
+        }
+    }
+
     /// Returns .1 status and headers, .2 content
     async fn handle_patch( api: &mut API, client: &Pool ) -> (String, Vec<u8>){
 
@@ -140,16 +478,16 @@ impl Response{
             // Request does not deviate from api:
             "" => match get_db_response( client, api ).await{
 
-                Ok( s ) => (Response::HTTP_200.to_string(), s.as_bytes().to_vec()),
+                Ok( s ) => (Response::HTTP_200.to_string(), s),
                 Err( e ) => {error!("...db problem on PATCH: {}", e);
-                    (Response::HTTP_400.to_string(), serde_json::to_string( 
+                    (Response::get_db_error_status( &e ), serde_json::to_string( 
                             &APIError{ message: e.to_string(), hint: "No hint".to_string()}).unwrap().as_bytes().to_vec()) 
                 } 
             },
 
             // Request DOES deviate from api, let's produce an error
             x => {error!("... bad PATCH request: `{}`.", x); 
-                (Response::HTTP_400.to_string(), 
+                (Response::get_deviation_status( api ), 
                  serde_json::to_string( &APIError{ message: x.to_string(), hint: "No hint".to_string()}).unwrap().as_bytes().to_vec()) } 
         }
     }
@@ -162,16 +500,16 @@ impl Response{
             // Request does not deviate from api:
             "" => match get_db_response( client, api ).await{
 
-                Ok( s ) => (Response::HTTP_200.to_string(), s.as_bytes().to_vec()),
+                Ok( s ) => (Response::HTTP_200.to_string(), s),
                 Err( e ) => {error!("...db problem on DELETE: {}", e);
-                    (Response::HTTP_400.to_string(), serde_json::to_string( 
+                    (Response::get_db_error_status( &e ), serde_json::to_string( 
                             &APIError{ message: e.to_string(), hint: "No hint".to_string()}).unwrap().as_bytes().to_vec())
                 } 
             },
 
             // Request DOES deviate from api:
             x => {error!("... bad DELETE request: `{}`.", x); 
-                (Response::HTTP_400.to_string(), 
+                (Response::get_deviation_status( api ), 
                  serde_json::to_string( &APIError{ message: x.to_string(), hint: "No hint".to_string()}).unwrap().as_bytes().to_vec()) } 
         }
     }
@@ -184,16 +522,16 @@ impl Response{
             // Request does not deviate from api
             "" => match get_db_response( client, api ).await{
 
-                Ok( s ) => (Response::HTTP_200.to_string(), s.as_bytes().to_vec()),
+                Ok( s ) => (Response::HTTP_200.to_string(), s),
                 Err( e ) => {error!("...db problem on POST: {}", e);
-                    (Response::HTTP_400.to_string(), serde_json::to_string( 
+                    (Response::get_db_error_status( &e ), serde_json::to_string( 
                             &APIError{ message: e.to_string(), hint: "No hint".to_string()}).unwrap().as_bytes().to_vec()) 
                 } 
             },
 
             // Request DOES deviate from api:
             x => {error!("... bad POST request: `{}`.", x); 
-                (Response::HTTP_400.to_string(), 
+                (Response::get_deviation_status( api ), 
                  serde_json::to_string( 
                      &APIError{ message: x.to_string(), hint: "No hint".to_string()}).unwrap().as_bytes().to_vec()) } 
         }
@@ -262,21 +600,166 @@ impl Response{
                 // Request does not deviate from api
                 "" => match get_db_response( client, api ).await{
 
-                    Ok( s ) => ( Response::HTTP_200.to_string(), s.as_bytes().to_vec() ),
+                    Ok( s ) => ( Response::HTTP_200.to_string(), s ),
 
                     Err( e ) => {info!("...db problem on GET: {}", e);
-                        ( Response::HTTP_400.to_string() ,format!("{} ", serde_json::to_string( 
+                        ( Response::get_db_error_status( &e ) ,format!("{} ", serde_json::to_string( 
                                 &APIError{ message: e.to_string(), hint: "No hint".to_string()}).unwrap()).as_bytes().to_vec())
                     }
                 },
 
                 // Request DOES deviate from api:
                 x => {error!("... bad GET request: `{}`.", x); 
-                    ( Response::HTTP_400.to_string(), 
+                    ( Response::get_deviation_status( api ), 
                       serde_json::to_string( 
                           &APIError{ message: x.to_string(), hint: "No hint".to_string() } 
                       ).unwrap().as_bytes().to_vec())}
             }
         }
     }
+
+    /// The API explorer page, loading the API document under the API's path prefix
+    fn get_api_explorer( conf: &MuscleConfig ) -> String {
+        let s_doc_path = match conf.api_prefix.as_str() {
+            "" => conf.api_doc_path.clone(),
+            s_prefix => format!( "{}/{}", s_prefix, conf.api_doc_path )
+        };
+        Response::API_EXPLORER.replace( "{{API_DOC_PATH}}", &s_doc_path )
+    }
+}
+
+#[cfg(test)]
+mod test_etag{
+    use super::*;
+
+    #[test]
+    fn etag() {
+        assert_eq!( Response::get_hash_etag( b"" ), "\"h-cbf29ce484222325\"" );
+        assert_eq!( Response::get_hash_etag( b"a" ), "\"h-af63dc4c8601ec8c\"" );
+        assert!( Response::etag_matches( "\"x\", W/\"h-af63dc4c8601ec8c\"", "\"h-af63dc4c8601ec8c\"" ) );
+        assert!( Response::etag_matches( "*", "\"3\"" ) );
+        assert!( !Response::etag_matches( "\"2\"", "\"3\"" ) );
+    }
+
+    #[test]
+    fn hash_etag() {
+        assert!( Response::is_hash_etag( &Response::get_hash_etag( b"a" ) ) );
+        assert!( Response::is_hash_etag( "W/\"h-af63dc4c8601ec8c\"" ) );
+        assert!( !Response::is_hash_etag( "\"1760000000000000\"" ) );
+        assert!( !Response::is_hash_etag( "\"h-1\"" ) );
+        assert!( !Response::is_hash_etag( "*" ) );
+    }
+}
+
+#[cfg(test)]
+mod test_auth_cookie{
+    use super::*;
+
+    #[test]
+    fn secure() {
+        let mut conf = crate::get_test_conf();
+        conf.csrf_cookie_name = "none".to_string();
+        assert_eq!( Response::get_auth_cookie_headers( br#"[{"token": "abc"}]"#, "token", &conf ), 
+            "Set-Cookie: pg_api_muscle_token=abc; Path=/; HttpOnly; Secure; SameSite=Strict\r\n" );
+        conf.auth_cookie_secure = false;
+        assert_eq!( Response::get_auth_cookie_headers( br#"{"token": "abc"}"#, "token", &conf ), 
+            "Set-Cookie: pg_api_muscle_token=abc; Path=/; HttpOnly; SameSite=Strict\r\n" );
+        assert_eq!( Response::get_auth_cookie_headers( b"{}", "token", &conf ), S_EMPTY );
+    }
+}
+
+#[cfg(test)]
+mod test_has_row{
+    use super::*;
+
+    #[test]
+    fn has_row() {
+        let json = | s: &str | serde_json::from_str::<serde_json::Value>( s ).unwrap();
+        assert!( Response::has_row( &json( r#"{"kapitel_id": 4}"# ) ) );
+        assert!( Response::has_row( &json( r#"[{"kapitel_id": 4}]"# ) ) );
+        assert!( !Response::has_row( &json( "{}" ) ) );
+        assert!( !Response::has_row( &json( "[]" ) ) );
+        assert!( !Response::has_row( &serde_json::Value::Null ) );
+    }
+}
+
+#[cfg(test)]
+mod test_batch{
+    use super::*;
+
+    #[test]
+    fn operation() {
+        let ops: Vec<BatchOperation> = serde_json::from_str( r#"[{"method": "DELETE", "path": "kapitel", "query": "kapitel_id=eq.1"},
+            {"method": "PATCH", "path": "kapitel", "query": {"kapitel_id": "eq.2", "titel": "eq.Zwei & drei", "n": 3}, "body": {"titel": "Zwei"}},
+            {"method": "POST", "path": "kapitel", "body": [1, "a"]}]"# ).unwrap();
+        assert_eq!( ( ops[0].get_query(), ops[0].get_body() ), ( "kapitel_id=eq.1".to_string(), S_EMPTY ) );
+        assert_eq!( ops[1].get_query(), "kapitel_id=eq.2&n=3&titel=eq.Zwei+%26+drei" );
+        assert_eq!( ops[1].get_body(), r#"{"titel":"Zwei"}"# );
+        assert_eq!( ( ops[2].get_query(), ops[2].get_body() ), ( S_EMPTY, r#"[1,"a"]"#.to_string() ) );
+        assert!( serde_json::from_str::<Vec<BatchOperation>>( r#"[{"path": "kapitel"}]"# ).is_err() );
+    }
+
+    #[test]
+    fn database() {
+        let conf = crate::get_test_conf();
+        let mut api = API::from_routing_json( r#"{"paths": {"kapitel": {"delete": {}}, "alt": {"delete": {"x-database": "alt"}},
+            "neu": {"delete": {"x-database": "default"}}}}"# );
+        assert_eq!( Response::get_batch_database_problem( api.set_test_request( "DELETE /kapitel HTTP/1.1\r\n\r\n" ), &conf ), None );
+        assert_eq!( Response::get_batch_database_problem( api.set_test_request( "DELETE /neu HTTP/1.1\r\n\r\n" ), &conf ), None );
+        assert!( Response::get_batch_database_problem( api.set_test_request( "DELETE /alt HTTP/1.1\r\n\r\n" ), &conf ).is_some() );
+    }
+}
+
+#[cfg(test)]
+mod test_api_explorer{
+    use super::*;
+
+    #[test]
+    fn doc_path() {
+        let ini = tini::Ini::from_string( "[Api.v2]\nprefix=/v2/\napi_conf=v2.json\napi_doc_path=openapi.json\n" ).unwrap();
+        let confs = crate::read_api_sections( &ini, &crate::get_test_conf(), "" );
+        assert!( Response::get_api_explorer( &confs[1] ).contains( "fetch(\"/v2/openapi.json\"" ) );
+        let mut conf = crate::get_test_conf();
+        conf.api_doc_path = "openapi.json".to_string();
+        assert!( Response::get_api_explorer( &conf ).contains( "fetch(\"/openapi.json\"" ) );
+    }
+}
+
+#[cfg(test)]
+mod test_db_error_status{
+    use super::*;
+
+    #[test]
+    fn status() {
+        assert_eq!( Response::get_db_error_status( ERR_NO_ROWS ), Response::HTTP_404 );
+        assert_eq!( Response::get_db_error_status( ERR_PRECONDITION ), Response::HTTP_412 );
+        assert_eq!( Response::get_db_error_status( &format!("{} `web_anon`", ERR_ANON_ROLE) ), Response::HTTP_500 );
+        assert_eq!( Response::get_db_error_status( "Database could not complete the request" ), Response::HTTP_400 );
+    }
+}
+
+#[cfg(test)]
+mod test_pool_timeout{
+    use super::*;
+
+    #[tokio::test]
+    async fn no_connection() {
+        // a database that never answers keeps the only connection busy
+        let listener = tokio::net::TcpListener::bind( "127.0.0.1:0" ).await.unwrap();
+        let conf = crate::get_test_conf();
+        let mut db_conf = conf.get_database( "default" ).unwrap().clone();
+        db_conf.host = "127.0.0.1".to_string();
+        db_conf.port = Some( listener.local_addr().unwrap().port() );
+        db_conf.pool_max_size = Some( 1 );
+        db_conf.pool_wait_timeout_ms = 10;
+        let pool = crate::get_db_pool( &db_conf );
+        let busy = pool.clone();
+        tokio::spawn( async move { busy.get().await } );
+        tokio::time::sleep( std::time::Duration::from_millis( 10 ) ).await;
+
+        let mut api = API::from_routing_json( r#"{"paths": {}}"# );
+        let e = begin_batch( &pool, &mut api ).await.err().unwrap();
+        assert!( e.contains( "Timeout" ) );
+        assert_eq!( Response::get_db_error_status( &e ), Response::HTTP_503 );
+    }
 }